@@ -0,0 +1,9 @@
+MEMORY
+{
+  /* Sectors 0 and 1, the settings and the application follow */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 32K
+  /* Bottom of the RAM, the crash report of the application is kept at the top */
+  RAM : ORIGIN = 0x20000000, LENGTH = 16K
+}
+
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
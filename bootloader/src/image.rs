@@ -0,0 +1,198 @@
+use core::ops::Range;
+
+use cortex_m::peripheral::SCB;
+use driver::{
+    APP_ADDRESS, APP_LEN, APP_SECTORS, DOWNLOAD_ADDRESS, DOWNLOAD_SECTOR_SIZE, DOWNLOAD_SECTORS,
+};
+use stm32f4xx_hal::flash::{self, FlashExt};
+use stm32f4xx_hal::pac::FLASH;
+use sysex::{Nak, crc32};
+
+const IMAGE_MAGIC: u32 = 0x696d_6731;
+// Magic, length, CRC-32 and installation marks of the downloaded image, which follows
+const HEADER_LEN: usize = 16;
+// The marks are bytes that stay erased until they are written once: the copy started mark is
+// written before the application sectors are erased, the installed mark once the copy is verified
+const COPY_STARTED_OFFSET: usize = 12;
+const INSTALLED_OFFSET: usize = 13;
+const MARK: u8 = 0;
+// Values of the initial stack pointer
+const RAM: Range<u32> = 0x2000_0000..0x2002_0001;
+
+struct Header {
+    len: usize,
+    crc: u32,
+    copy_started: bool,
+    installed: bool,
+}
+
+// Image being received
+struct Download {
+    len: usize,
+    crc: u32,
+    received: usize,
+    // Bytes of the download sectors erased so far
+    erased: usize,
+}
+
+/// Application image and image downloaded to replace it.
+pub struct Images {
+    flash: FLASH,
+    download: Option<Download>,
+}
+
+impl Images {
+    pub fn new(flash: FLASH) -> Self {
+        Self {
+            flash,
+            download: None,
+        }
+    }
+
+    /// Copies the downloaded image to the application sectors if it is not installed yet, or if
+    /// its copy was interrupted.
+    /// Once installed the image is never copied again, a firmware flashed later with a probe
+    /// replaces it.
+    pub fn restore(&mut self) -> Result<(), flash::Error> {
+        let Some(header) = Self::header() else {
+            return Ok(());
+        };
+        if header.installed {
+            return Ok(());
+        }
+        let base = self.flash.address();
+        let mut flash = self.flash.unlocked();
+        if !header.copy_started {
+            flash.program(DOWNLOAD_ADDRESS + COPY_STARTED_OFFSET - base, [MARK].iter())?;
+        }
+        for sector in APP_SECTORS {
+            flash.erase(sector)?;
+        }
+        let image = bytes(DOWNLOAD_ADDRESS + HEADER_LEN, header.len);
+        flash.program(APP_ADDRESS - base, image.iter())?;
+        if Self::app_matches(&header) {
+            flash.program(DOWNLOAD_ADDRESS + INSTALLED_OFFSET - base, [MARK].iter())?;
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the application can be started.
+    pub fn app_valid(&self) -> bool {
+        // Only a copy that did not complete leaves a damaged application, an installed image may
+        // have been replaced with a probe
+        vectors_valid(APP_ADDRESS)
+            && Self::header()
+                .is_none_or(|h| h.installed || !h.copy_started || Self::app_matches(&h))
+    }
+
+    /// Starts to receive an image of `len` bytes.
+    pub fn begin(&mut self, len: usize, crc: u32) -> Result<(), Nak> {
+        if len == 0 || len > APP_LEN {
+            self.download = None;
+            return Err(Nak::TooLong);
+        }
+        self.download = Some(Download {
+            len,
+            crc,
+            received: 0,
+            erased: 0,
+        });
+        Ok(())
+    }
+
+    /// Writes the bytes of the image from `offset`.
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Nak> {
+        let base = self.flash.address();
+        let Some(download) = self
+            .download
+            .as_mut()
+            .filter(|d| d.received == offset && offset + data.len() <= d.len)
+        else {
+            self.download = None;
+            return Err(Nak::Sequence);
+        };
+        let start = HEADER_LEN + offset;
+        let mut flash = self.flash.unlocked();
+        // The sectors are erased when the image reaches them, the first one holds the header
+        while download.erased < start + data.len() {
+            flash
+                .erase(DOWNLOAD_SECTORS[download.erased / DOWNLOAD_SECTOR_SIZE])
+                .map_err(|_| Nak::Invalid)?;
+            download.erased += DOWNLOAD_SECTOR_SIZE;
+        }
+        flash
+            .program(DOWNLOAD_ADDRESS + start - base, data.iter())
+            .map_err(|_| Nak::Invalid)?;
+        download.received += data.len();
+        Ok(())
+    }
+
+    /// Verifies the received image and installs it.
+    pub fn end(&mut self) -> Result<(), Nak> {
+        let download = self
+            .download
+            .take()
+            .filter(|d| d.received == d.len)
+            .ok_or(Nak::Sequence)?;
+        let image = bytes(DOWNLOAD_ADDRESS + HEADER_LEN, download.len);
+        if crc32(image) != download.crc || !vectors_valid(DOWNLOAD_ADDRESS + HEADER_LEN) {
+            return Err(Nak::Invalid);
+        }
+        // The header is written last so that an incomplete image is never installed
+        let mut header = [0xff; COPY_STARTED_OFFSET];
+        header[0..4].copy_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&(download.len as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&download.crc.to_le_bytes());
+        let base = self.flash.address();
+        self.flash
+            .unlocked()
+            .program(DOWNLOAD_ADDRESS - base, header.iter())
+            .map_err(|_| Nak::Invalid)?;
+        self.restore().map_err(|_| Nak::Invalid)?;
+        if self.app_valid() {
+            Ok(())
+        } else {
+            Err(Nak::Invalid)
+        }
+    }
+
+    fn header() -> Option<Header> {
+        let len = word(DOWNLOAD_ADDRESS + 4) as usize;
+        (word(DOWNLOAD_ADDRESS) == IMAGE_MAGIC && len <= APP_LEN).then(|| Header {
+            len,
+            crc: word(DOWNLOAD_ADDRESS + 8),
+            copy_started: bytes(DOWNLOAD_ADDRESS + COPY_STARTED_OFFSET, 1)[0] == MARK,
+            installed: bytes(DOWNLOAD_ADDRESS + INSTALLED_OFFSET, 1)[0] == MARK,
+        })
+    }
+
+    fn app_matches(header: &Header) -> bool {
+        crc32(bytes(APP_ADDRESS, header.len)) == header.crc
+    }
+}
+
+/// Starts the application.
+///
+/// # Safety
+/// The vector table of the application must be valid, see [`Images::app_valid`].
+pub unsafe fn start_app() -> ! {
+    unsafe {
+        (*SCB::PTR).vtor.write(APP_ADDRESS as u32);
+        cortex_m::asm::bootload(APP_ADDRESS as *const u32)
+    }
+}
+
+// Checks the stack pointer and the reset vector of an image linked for the application sectors
+fn vectors_valid(address: usize) -> bool {
+    let reset = word(address + 4) as usize;
+    RAM.contains(&word(address)) && (APP_ADDRESS..APP_ADDRESS + APP_LEN).contains(&reset)
+}
+
+fn bytes(address: usize, len: usize) -> &'static [u8] {
+    // SAFETY: the flash is memory mapped, it is only written through `Images`
+    unsafe { core::slice::from_raw_parts(address as *const u8, len) }
+}
+
+fn word(address: usize) -> u32 {
+    u32::from_le_bytes([0, 1, 2, 3].map(|i| bytes(address, 4)[i]))
+}
@@ -0,0 +1,131 @@
+//! Resident bootloader, installs the firmwares received over MIDI SysEx.
+//!
+//! The application is started unless it asked for an update or its image is not valid.
+//! A new image is received in the download sectors and verified before it is copied to the
+//! application sectors. It stays in the download sectors until its copy is verified, so that a copy
+//! interrupted by a reset is completed at the next boot. An image that fails the verification is
+//! never installed and the previous application keeps running. A firmware flashed with a probe
+//! replaces an installed image.
+
+#![no_main]
+#![no_std]
+
+extern crate alloc;
+
+mod image;
+
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::entry;
+use embedded_alloc::LlffHeap as Heap;
+use stm32f4xx_hal::{
+    block, pac,
+    prelude::*,
+    serial::{
+        Config, Serial,
+        config::{DmaConfig, StopBits::STOP1},
+    },
+};
+use sysex::{CHUNK_LEN, Message, Nak, ParseError, SYSEX_END, SYSEX_START};
+
+use crate::image::{Images, start_app};
+
+// Holds the messages being decoded
+const HEAP_SIZE: usize = 4 * 1024;
+const SYSEX_LEN: usize = 128;
+
+#[global_allocator]
+static HEAP: Heap = Heap::empty();
+
+#[panic_handler]
+fn panic(_: &PanicInfo) -> ! {
+    // The update can be sent again after the restart
+    SCB::sys_reset()
+}
+
+#[entry]
+fn main() -> ! {
+    let dp = pac::Peripherals::take().unwrap();
+
+    let mut images = Images::new(dp.FLASH);
+    let requested = driver::take_update_request();
+    // If the restoration fails the application is not valid and an update is needed
+    let _ = images.restore();
+    if !requested && images.app_valid() {
+        // SAFETY: the vector table of the application was checked
+        unsafe { start_app() }
+    }
+
+    static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
+    unsafe { HEAP.init(&raw mut HEAP_MEM as usize, HEAP_SIZE) }
+
+    let gpioa = dp.GPIOA.split();
+    let gpiob = dp.GPIOB.split();
+    let rcc = dp.RCC.constrain();
+    let clocks = rcc.cfgr.use_hse(25.MHz()).freeze();
+
+    // The play LED shows that the bootloader waits for a firmware
+    let mut led = driver::Led::new(gpioa.pa4.into_push_pull_output().erase());
+    led.set(true);
+
+    let serial: Serial<pac::USART1> = Serial::new(
+        dp.USART1,
+        (gpioa.pa15.into_alternate(), gpiob.pb3.into_alternate()),
+        Config::default()
+            .baudrate(31250.bps())
+            .wordlength_8()
+            .parity_none()
+            .stopbits(STOP1)
+            .dma(DmaConfig::None),
+        &clocks,
+    )
+    .unwrap();
+    let (mut tx, mut rx) = serial.split();
+
+    let mut sysex = heapless::Vec::<u8, SYSEX_LEN>::new();
+    loop {
+        let Ok(byte) = block!(rx.read()) else {
+            sysex.clear();
+            continue;
+        };
+        // Only the SysEx messages are used, the real-time messages can be interleaved
+        if byte == SYSEX_START {
+            sysex.clear();
+        } else if sysex.is_empty() || byte >= 0xf8 {
+            continue;
+        }
+        if sysex.push(byte).is_err() {
+            sysex.clear();
+            continue;
+        }
+        if byte != SYSEX_END {
+            continue;
+        }
+
+        let message = Message::parse(&sysex);
+        sysex.clear();
+        let result = match &message {
+            Ok(Message::FirmwareBegin { len, crc }) => images.begin(*len as usize, *crc),
+            Ok(Message::Chunk { index, data }) => images.write(*index as usize * CHUNK_LEN, data),
+            Ok(Message::End) => images.end(),
+            // Replies of another device
+            Ok(Message::Ack | Message::Nak(_)) => continue,
+            Ok(_) => Err(Nak::Sequence),
+            Err(ParseError::Checksum) => Err(Nak::Checksum),
+            Err(_) => continue,
+        };
+        let reply = match result {
+            Ok(()) => Message::Ack,
+            Err(nak) => Message::Nak(nak),
+        };
+        let _ = driver::write(&mut tx, &reply.to_sysex());
+        let _ = block!(tx.flush());
+
+        // The new application starts like after a power cycle
+        if result.is_ok() && message == Ok(Message::End) {
+            SCB::sys_reset();
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use cortex_m::peripheral::SCB;
+
+/// Address of the application, starting with its vector table.
+pub const APP_ADDRESS: usize = 0x0801_0000;
+pub const APP_SECTORS: [u8; 2] = [4, 5];
+pub const APP_LEN: usize = 192 * 1024;
+/// New firmwares are received here before being copied to the application sectors.
+pub const DOWNLOAD_ADDRESS: usize = 0x0804_0000;
+pub const DOWNLOAD_SECTORS: [u8; 2] = [6, 7];
+pub const DOWNLOAD_SECTOR_SIZE: usize = 128 * 1024;
+
+// End of the RAM, left out of the memory of the bootloader and of the application
+const UPDATE_REQUEST: *mut u32 = 0x2001_fff8 as *mut u32;
+const UPDATE_MAGIC: u32 = 0x7570_6474;
+
+/// Restarts in the bootloader to receive a new firmware.
+pub fn reboot_to_bootloader() -> ! {
+    // SAFETY: the word is not used by any program
+    unsafe { UPDATE_REQUEST.write_volatile(UPDATE_MAGIC) };
+    SCB::sys_reset()
+}
+
+/// Returns `true` if the application asked for a new firmware before restarting, and clears the
+/// request.
+pub fn take_update_request() -> bool {
+    // SAFETY: the word is not used by any program
+    unsafe {
+        let requested = UPDATE_REQUEST.read_volatile() == UPDATE_MAGIC;
+        UPDATE_REQUEST.write_volatile(0);
+        requested
+    }
+}
@@ -0,0 +1,133 @@
+/// Maximum number of characters in a line of [`DisplayText`].
+/// Lines wider than the screen are scrolled or truncated by the driver.
+pub const LINE_CAPACITY: usize = 64;
+
+pub type Line = heapless::String<LINE_CAPACITY>;
+
+// Inserted between the end and the beginning of a scrolling line
+const MARQUEE_GAP: &str = "   ";
+
+/// Text to show on the screen, one entry per line.
+#[derive(Default, Clone, PartialEq)]
+pub struct DisplayText {
+    pub lines: [Line; 4],
+    pub scroll: ScrollMode,
+    pub graphic: Graphic,
+}
+
+/// How lines wider than the screen are shown.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScrollMode {
+    /// Only the beginning of the line is shown.
+    Truncate,
+    /// The line scrolls by one character every `step_ms` milliseconds.
+    Marquee { step_ms: u32 },
+}
+
+impl Default for ScrollMode {
+    fn default() -> Self {
+        ScrollMode::Marquee { step_ms: 300 }
+    }
+}
+
+/// Optional drawing shown below the text on graphical displays.
+/// Character displays ignore it.
+#[derive(Default, Clone, PartialEq)]
+pub enum Graphic {
+    #[default]
+    None,
+    /// One cell per step, `current` is highlighted.
+    StepGrid {
+        steps: heapless::Vec<bool, 16>,
+        current: u8,
+    },
+    /// CC automation values (0-127) drawn from left to right.
+    Waveform { values: heapless::Vec<u8, 32> },
+}
+
+/// Common interface of the screens supported by the firmware.
+pub trait Display {
+    /// Render `text` on the screen.
+    fn update(&mut self, text: &DisplayText);
+    /// Advance the scrolling of long lines by `elapsed_ms` and redraw them if needed.
+    fn tick(&mut self, elapsed_ms: u32);
+}
+
+/// Screen selected at compile time.
+#[cfg(not(feature = "ssd1306"))]
+pub type Screen = crate::Lcd;
+#[cfg(feature = "ssd1306")]
+pub type Screen = crate::Oled;
+
+/// Scrolling state of the lines of a [`DisplayText`] on a screen `WIDTH` characters wide.
+#[derive(Default)]
+pub(crate) struct Marquee<const WIDTH: usize> {
+    offsets: [usize; 4],
+    elapsed_ms: u32,
+}
+
+impl<const WIDTH: usize> Marquee<WIDTH> {
+    /// Restarts the scrolling of the lines that differ between `old` and `new`.
+    pub(crate) fn reset_changed(&mut self, old: &DisplayText, new: &DisplayText) {
+        old.lines
+            .iter()
+            .zip(new.lines.iter())
+            .zip(self.offsets.iter_mut())
+            .filter(|((old, new), _)| old != new)
+            .for_each(|(_, offset)| *offset = 0);
+    }
+
+    /// Advances the scrolling, returns `true` if the visible text changed.
+    pub(crate) fn tick(&mut self, text: &DisplayText, elapsed_ms: u32) -> bool {
+        let ScrollMode::Marquee { step_ms } = text.scroll else {
+            return false;
+        };
+        let step_ms = step_ms.max(1);
+        self.elapsed_ms += elapsed_ms;
+        let steps = (self.elapsed_ms / step_ms) as usize;
+        self.elapsed_ms %= step_ms;
+        if steps == 0 {
+            return false;
+        }
+
+        let mut moved = false;
+        text.lines
+            .iter()
+            .zip(self.offsets.iter_mut())
+            .for_each(|(line, offset)| {
+                let len = line.chars().count();
+                if len > WIDTH {
+                    *offset = (*offset + steps) % (len + MARQUEE_GAP.chars().count());
+                    moved = true;
+                }
+            });
+        moved
+    }
+
+    /// Returns the visible part of line `row` of `text`.
+    /// The characters that are not ASCII are replaced by `?`, so that the window holds `WIDTH`
+    /// characters of one byte each.
+    pub(crate) fn window(&self, text: &DisplayText, row: usize) -> heapless::String<WIDTH> {
+        let line = &text.lines[row];
+        let mut window = heapless::String::new();
+        let scrolls = matches!(text.scroll, ScrollMode::Marquee { .. });
+        if scrolls && line.chars().count() > WIDTH {
+            line.chars()
+                .chain(MARQUEE_GAP.chars())
+                .cycle()
+                .skip(self.offsets[row])
+                .take(WIDTH)
+                .for_each(|c| window.push(ascii(c)).unwrap());
+        } else {
+            line.chars()
+                .take(WIDTH)
+                .for_each(|c| window.push(ascii(c)).unwrap());
+        }
+        window
+    }
+}
+
+// The screens only show ASCII
+fn ascii(c: char) -> char {
+    if c.is_ascii() { c } else { '?' }
+}
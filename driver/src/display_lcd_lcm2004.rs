@@ -1,28 +1,31 @@
-use log::{debug, error, info};
+use log::{error, info};
 use stm32f4xx_hal::{
-    pac::{I2C1, TIM3},
+    pac::{I2C1, TIM5},
     timer::DelayUs,
 };
 
-const LCD_ADDRESS: u8 = 0x27;
+use crate::display::Marquee;
+use crate::{Display, DisplayText};
 
-#[derive(Default)]
-pub struct DisplayText {
-    pub lines: [heapless::String<16>; 4],
-}
+const LCD_ADDRESS: u8 = 0x27;
+const LCD_WIDTH: usize = 20;
 
 pub struct Lcd {
     i2c: stm32f4xx_hal::i2c::I2c<I2C1>,
-    delay: DelayUs<TIM3>,
-    current_display: DisplayText,
+    delay: DelayUs<TIM5>,
+    text: DisplayText,
+    marquee: Marquee<LCD_WIDTH>,
+    current_display: [heapless::String<LCD_WIDTH>; 4],
 }
 
 impl Lcd {
-    pub fn new(i2c: stm32f4xx_hal::i2c::I2c<I2C1>, delay: DelayUs<TIM3>) -> Option<Self> {
+    pub fn new(i2c: stm32f4xx_hal::i2c::I2c<I2C1>, delay: DelayUs<TIM5>) -> Option<Self> {
         let mut result = Self {
             i2c,
             delay,
-            current_display: DisplayText::default(),
+            text: DisplayText::default(),
+            marquee: Marquee::default(),
+            current_display: Default::default(),
         };
         match result.init() {
             Some(mut lcd) => {
@@ -42,7 +45,7 @@ impl Lcd {
         lcd: &mut lcd_lcm1602_i2c::sync_lcd::Lcd<
             '_,
             stm32f4xx_hal::i2c::I2c<I2C1>,
-            stm32f4xx_hal::timer::Delay<stm32f4xx_hal::pac::TIM3, 1000000>,
+            stm32f4xx_hal::timer::Delay<stm32f4xx_hal::pac::TIM5, 1000000>,
         >,
         row: usize,
         col: usize,
@@ -50,7 +53,7 @@ impl Lcd {
         cursor_col: &mut u8,
     ) {
         let display_row = (row % 2) as u8;
-        let display_col = (col + (row / 2) * 20) as u8;
+        let display_col = (col + (row / 2) * LCD_WIDTH) as u8;
         if display_row != *cursor_row || display_col != *cursor_col {
             lcd.set_cursor(display_row, display_col).unwrap();
             *cursor_col = display_col;
@@ -58,16 +61,18 @@ impl Lcd {
         }
     }
 
-    pub fn update(&mut self, text: &DisplayText) {
+    fn render(&mut self) {
+        let windows: [heapless::String<LCD_WIDTH>; 4] =
+            core::array::from_fn(|row| self.marquee.window(&self.text, row));
         let mut lcd = lcd_lcm1602_i2c::sync_lcd::Lcd::new(&mut self.i2c, &mut self.delay)
             .with_address(LCD_ADDRESS)
             .with_rows(2);
         lcd.return_home().unwrap();
         let mut cursor_row = 0;
         let mut cursor_col = 0;
-        text.lines
+        windows
             .iter()
-            .zip(self.current_display.lines.iter())
+            .zip(self.current_display.iter())
             .enumerate()
             .for_each(|(row, (new, old))| {
                 new.chars()
@@ -82,7 +87,7 @@ impl Lcd {
                                 &mut cursor_row,
                                 &mut cursor_col,
                             );
-                            lcd.write_str(&(new.as_str())[col..(col + 1)]).unwrap();
+                            lcd.write_str(new_c.encode_utf8(&mut [0; 4])).unwrap();
                             cursor_col += 1;
                         }
                     });
@@ -90,8 +95,16 @@ impl Lcd {
                     let col = old.len();
                     Self::update_cursor_pos(&mut lcd, row, col, &mut cursor_row, &mut cursor_col);
                     lcd.write_str(&(new.as_str())[col..]).unwrap();
+                    cursor_col += (new.len() - col) as u8;
+                } else if new.len() < old.len() {
+                    // Blank the end of the previous text
+                    let col = new.len();
+                    Self::update_cursor_pos(&mut lcd, row, col, &mut cursor_row, &mut cursor_col);
+                    (col..old.len()).for_each(|_| lcd.write_str(" ").unwrap());
+                    cursor_col += (old.len() - col) as u8;
                 }
             });
+        self.current_display = windows;
     }
 
     fn init(
@@ -100,7 +113,7 @@ impl Lcd {
         lcd_lcm1602_i2c::sync_lcd::Lcd<
             '_,
             stm32f4xx_hal::i2c::I2c<I2C1>,
-            stm32f4xx_hal::timer::Delay<stm32f4xx_hal::pac::TIM3, 1000000>,
+            stm32f4xx_hal::timer::Delay<stm32f4xx_hal::pac::TIM5, 1000000>,
         >,
     > {
         lcd_lcm1602_i2c::sync_lcd::Lcd::new(&mut self.i2c, &mut self.delay)
@@ -111,3 +124,17 @@ impl Lcd {
             .ok()
     }
 }
+
+impl Display for Lcd {
+    fn update(&mut self, text: &DisplayText) {
+        self.marquee.reset_changed(&self.text, text);
+        self.text = text.clone();
+        self.render();
+    }
+
+    fn tick(&mut self, elapsed_ms: u32) {
+        if self.marquee.tick(&self.text, elapsed_ms) {
+            self.render();
+        }
+    }
+}
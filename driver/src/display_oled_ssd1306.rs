@@ -0,0 +1,128 @@
+use embedded_graphics::{
+    mono_font::{MonoTextStyle, ascii::FONT_6X10},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+use log::{error, info};
+use ssd1306::{I2CDisplayInterface, Ssd1306, mode::BufferedGraphicsMode, prelude::*};
+use stm32f4xx_hal::pac::I2C1;
+
+use crate::display::Marquee;
+use crate::{Display, DisplayText, Graphic};
+
+const LINE_HEIGHT: i32 = 10;
+const GRAPHIC_TOP: i32 = 42;
+const GRAPHIC_HEIGHT: u32 = 22;
+const WIDTH: u32 = 128;
+// Number of characters of `FONT_6X10` in a line
+const TEXT_WIDTH: usize = 21;
+
+type Ssd1306I2c = Ssd1306<
+    I2CInterface<stm32f4xx_hal::i2c::I2c<I2C1>>,
+    DisplaySize128x64,
+    BufferedGraphicsMode<DisplaySize128x64>,
+>;
+
+pub struct Oled {
+    display: Ssd1306I2c,
+    text: DisplayText,
+    marquee: Marquee<TEXT_WIDTH>,
+}
+
+impl Oled {
+    pub fn new(i2c: stm32f4xx_hal::i2c::I2c<I2C1>) -> Option<Self> {
+        let interface = I2CDisplayInterface::new(i2c);
+        let mut display = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
+            .into_buffered_graphics_mode();
+        if display.init().is_err() {
+            error!("Screen initialization failed");
+            return None;
+        }
+        info!("Screen detected");
+        Some(Self {
+            display,
+            text: DisplayText::default(),
+            marquee: Marquee::default(),
+        })
+    }
+
+    fn draw_step_grid(&mut self, steps: &[bool], current: u8) {
+        if steps.is_empty() {
+            return;
+        }
+        let cell = WIDTH / steps.len() as u32;
+        let outline = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+        let fill = PrimitiveStyle::with_fill(BinaryColor::On);
+        steps.iter().enumerate().for_each(|(i, &on)| {
+            let x = (i as u32 * cell) as i32;
+            let size = if i == current as usize {
+                Size::new(cell - 1, GRAPHIC_HEIGHT)
+            } else {
+                Size::new(cell - 1, GRAPHIC_HEIGHT - 6)
+            };
+            let rect = Rectangle::new(Point::new(x, GRAPHIC_TOP), size);
+            let style = if on { fill } else { outline };
+            rect.into_styled(style).draw(&mut self.display).ok();
+        });
+    }
+
+    fn draw_waveform(&mut self, values: &[u8]) {
+        if values.len() < 2 {
+            return;
+        }
+        let style = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+        let dx = (WIDTH - 1) as i32 / (values.len() - 1) as i32;
+        let y = |v: u8| {
+            GRAPHIC_TOP + GRAPHIC_HEIGHT as i32
+                - 1
+                - (v.min(127) as i32 * (GRAPHIC_HEIGHT as i32 - 1)) / 127
+        };
+        values.windows(2).enumerate().for_each(|(i, w)| {
+            let x = i as i32 * dx;
+            Line::new(Point::new(x, y(w[0])), Point::new(x + dx, y(w[1])))
+                .into_styled(style)
+                .draw(&mut self.display)
+                .ok();
+        });
+    }
+
+    fn render(&mut self) {
+        self.display.clear_buffer();
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        (0..self.text.lines.len()).for_each(|row| {
+            let line = self.marquee.window(&self.text, row);
+            Text::with_baseline(
+                line.as_str(),
+                Point::new(0, row as i32 * LINE_HEIGHT),
+                style,
+                Baseline::Top,
+            )
+            .draw(&mut self.display)
+            .ok();
+        });
+        match self.text.graphic.clone() {
+            Graphic::None => (),
+            Graphic::StepGrid { steps, current } => self.draw_step_grid(&steps, current),
+            Graphic::Waveform { values } => self.draw_waveform(&values),
+        }
+        if self.display.flush().is_err() {
+            error!("Screen update failed");
+        }
+    }
+}
+
+impl Display for Oled {
+    fn update(&mut self, text: &DisplayText) {
+        self.marquee.reset_changed(&self.text, text);
+        self.text = text.clone();
+        self.render();
+    }
+
+    fn tick(&mut self, elapsed_ms: u32) {
+        if self.marquee.tick(&self.text, elapsed_ms) {
+            self.render();
+        }
+    }
+}
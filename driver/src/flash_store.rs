@@ -0,0 +1,281 @@
+use stm32f4xx_hal::flash::{self, FlashExt};
+use stm32f4xx_hal::pac::FLASH;
+use sysex::crc32;
+use thiserror::Error;
+
+/// Longest value that can be stored under a key.
+pub const MAX_VALUE_LEN: usize = 4096;
+
+// Sectors of the store with their offset in the flash, between the bootloader and the program
+const SECTORS: [(u8, usize); 2] = [(2, 0x8000), (3, 0xc000)];
+const SECTOR_SIZE: usize = 16 * 1024;
+// Start of a sector in use, followed by its generation
+const MAGIC: u32 = 0x4d53_4b56;
+const SECTOR_HEADER_SIZE: usize = 8;
+// Key, length and CRC of the value
+const RECORD_HEADER_SIZE: usize = 8;
+// Key of the erased flash, marks the end of the records
+const ERASED_KEY: u16 = 0xffff;
+// Keys copied by a compaction
+const MAX_KEYS: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Key {0:#06x} is reserved.")]
+    ReservedKey(u16),
+    #[error("Value of {0} bytes is too long.")]
+    TooLong(usize),
+    #[error("Store is full.")]
+    Full,
+    #[error("Flash error: {0:?}.")]
+    Flash(flash::Error),
+}
+
+/// Persistent key/value store.
+pub trait Store {
+    /// Returns the last value written under `key`.
+    fn read(&self, key: u16) -> Option<&[u8]>;
+
+    /// Writes `value` under `key`, an empty value removes the key.
+    fn write(&mut self, key: u16, value: &[u8]) -> Result<(), StoreError>;
+}
+
+/// Key/value store in two sectors of the internal flash.
+///
+/// The values are appended as CRC protected records to the active sector.
+/// When it is full, the last value of each key is copied to the other sector which becomes active,
+/// so both sectors wear at the same rate. A record cut by a reset is ignored.
+///
+/// Erasing a sector stalls the execution from the flash for a few hundred milliseconds.
+pub struct FlashStore {
+    flash: FLASH,
+    // Index of the active sector in SECTORS
+    active: usize,
+    generation: u32,
+    // Offset of the first free byte of the active sector
+    end: usize,
+}
+
+impl FlashStore {
+    /// Opens the store, the sectors are formatted if none holds a store.
+    pub fn new(flash: FLASH) -> Result<Self, StoreError> {
+        let mut store = Self {
+            flash,
+            active: 0,
+            generation: 0,
+            end: SECTOR_HEADER_SIZE,
+        };
+        let active = (0..SECTORS.len())
+            .filter_map(|i| store.generation_of(i).map(|g| (i, g)))
+            .max_by_key(|(_, generation)| *generation);
+        match active {
+            Some((active, generation)) => {
+                store.active = active;
+                store.generation = generation;
+                let mut records = store.records(active);
+                records.by_ref().for_each(drop);
+                let (end, corrupt) = (records.offset, records.corrupt);
+                store.end = end;
+                // The space of a damaged record cannot be written again
+                if corrupt {
+                    store.compact()?;
+                }
+            }
+            None => store.format(0, 1)?,
+        }
+        Ok(store)
+    }
+
+    // Content of a sector
+    fn sector(&self, index: usize) -> &[u8] {
+        let start = self.flash.address() + SECTORS[index].1;
+        // SAFETY: the sector is memory mapped and only written through `&mut self`
+        unsafe { core::slice::from_raw_parts(start as *const u8, SECTOR_SIZE) }
+    }
+
+    fn generation_of(&self, index: usize) -> Option<u32> {
+        let sector = self.sector(index);
+        let magic = u32::from_le_bytes(sector[0..4].try_into().ok()?);
+        let generation = u32::from_le_bytes(sector[4..8].try_into().ok()?);
+        (magic == MAGIC && generation != u32::MAX).then_some(generation)
+    }
+
+    fn records(&self, index: usize) -> Records<'_> {
+        Records {
+            sector: self.sector(index),
+            offset: SECTOR_HEADER_SIZE,
+            corrupt: false,
+        }
+    }
+
+    // Erases the sector and makes it the active one
+    fn format(&mut self, index: usize, generation: u32) -> Result<(), StoreError> {
+        self.erase(index)?;
+        self.program(index, 0, &Self::sector_header(generation))?;
+        self.active = index;
+        self.generation = generation;
+        self.end = SECTOR_HEADER_SIZE;
+        Ok(())
+    }
+
+    // Copies the last value of each key to the other sector
+    fn compact(&mut self) -> Result<(), StoreError> {
+        let mut keys = heapless::Vec::<u16, MAX_KEYS>::new();
+        for (key, _) in self.records(self.active) {
+            if !keys.contains(&key) {
+                keys.push(key).map_err(|_| StoreError::Full)?;
+            }
+        }
+        let target = (self.active + 1) % SECTORS.len();
+        self.erase(target)?;
+        let mut end = SECTOR_HEADER_SIZE;
+        for key in keys {
+            let Some(value) = self.read(key) else {
+                continue;
+            };
+            let size = record_size(value.len());
+            if end + size > SECTOR_SIZE {
+                return Err(StoreError::Full);
+            }
+            // SAFETY: the value is in the active sector, which is not written during the compaction
+            let value = unsafe { core::slice::from_raw_parts(value.as_ptr(), value.len()) };
+            self.program_record(target, end, key, value)?;
+            end += size;
+        }
+        // The header is written last so that an interrupted compaction leaves the active sector in use
+        let generation = self.generation + 1;
+        self.program(target, 0, &Self::sector_header(generation))?;
+        self.active = target;
+        self.generation = generation;
+        self.end = end;
+        Ok(())
+    }
+
+    fn sector_header(generation: u32) -> [u8; SECTOR_HEADER_SIZE] {
+        let mut header = [0; SECTOR_HEADER_SIZE];
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&generation.to_le_bytes());
+        header
+    }
+
+    fn erase(&mut self, index: usize) -> Result<(), StoreError> {
+        self.flash
+            .unlocked()
+            .erase(SECTORS[index].0)
+            .map_err(StoreError::Flash)
+    }
+
+    fn program(&mut self, index: usize, offset: usize, bytes: &[u8]) -> Result<(), StoreError> {
+        self.flash
+            .unlocked()
+            .program(SECTORS[index].1 + offset, bytes.iter())
+            .map_err(StoreError::Flash)
+    }
+
+    fn program_record(
+        &mut self,
+        index: usize,
+        offset: usize,
+        key: u16,
+        value: &[u8],
+    ) -> Result<(), StoreError> {
+        let mut header = [0; RECORD_HEADER_SIZE];
+        header[0..2].copy_from_slice(&key.to_le_bytes());
+        header[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        header[4..8].copy_from_slice(&record_crc(key, value).to_le_bytes());
+        // The padding stays erased
+        self.flash
+            .unlocked()
+            .program(SECTORS[index].1 + offset, header.iter().chain(value))
+            .map_err(StoreError::Flash)
+    }
+}
+
+impl Store for FlashStore {
+    fn read(&self, key: u16) -> Option<&[u8]> {
+        self.records(self.active)
+            .filter(|(k, _)| *k == key)
+            .last()
+            .map(|(_, value)| value)
+            .filter(|value| !value.is_empty())
+    }
+
+    fn write(&mut self, key: u16, value: &[u8]) -> Result<(), StoreError> {
+        if key == ERASED_KEY {
+            return Err(StoreError::ReservedKey(key));
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(StoreError::TooLong(value.len()));
+        }
+        // Writing the same value again would only wear the flash
+        if self.read(key).unwrap_or_default() == value {
+            return Ok(());
+        }
+        let size = record_size(value.len());
+        if self.end + size > SECTOR_SIZE {
+            self.compact()?;
+            if self.end + size > SECTOR_SIZE {
+                return Err(StoreError::Full);
+            }
+        }
+        self.program_record(self.active, self.end, key, value)?;
+        self.end += size;
+        Ok(())
+    }
+}
+
+// Records of a sector, in the order they were written
+struct Records<'a> {
+    sector: &'a [u8],
+    offset: usize,
+    // A record was cut by a reset
+    corrupt: bool,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let header = self
+                .sector
+                .get(self.offset..self.offset + RECORD_HEADER_SIZE)?;
+            let key = u16::from_le_bytes([header[0], header[1]]);
+            let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+            let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            if key == ERASED_KEY {
+                return None;
+            }
+            let start = self.offset + RECORD_HEADER_SIZE;
+            let Some(value) = self
+                .sector
+                .get(start..start + len)
+                .filter(|_| len <= MAX_VALUE_LEN)
+            else {
+                // The length was not written, the following records cannot be found
+                self.corrupt = true;
+                return None;
+            };
+            self.offset += record_size(len);
+            if record_crc(key, value) == crc {
+                return Some((key, value));
+            }
+            self.corrupt = true;
+        }
+    }
+}
+
+// Size of a record in the flash, the records are aligned to words
+fn record_size(len: usize) -> usize {
+    RECORD_HEADER_SIZE + len.next_multiple_of(4)
+}
+
+fn record_crc(key: u16, value: &[u8]) -> u32 {
+    let len = value.len() as u16;
+    crc32(
+        key.to_le_bytes()
+            .iter()
+            .chain(&len.to_le_bytes())
+            .chain(value),
+    )
+}
@@ -0,0 +1,53 @@
+use stm32f4xx_hal::gpio::{ErasedPin, Output};
+
+/// LED between a pin and the ground, lit when the pin is high.
+pub struct Led {
+    pin: ErasedPin<Output>,
+}
+
+impl Led {
+    pub fn new(mut pin: ErasedPin<Output>) -> Self {
+        pin.set_low();
+        Self { pin }
+    }
+
+    pub fn set(&mut self, on: bool) {
+        if on {
+            self.pin.set_high();
+        } else {
+            self.pin.set_low();
+        }
+    }
+}
+
+/// LED lit for a short time after each event, switched off by [`PulseLed::tick`].
+pub struct PulseLed {
+    led: Led,
+    off_at_ms: Option<u32>,
+}
+
+impl PulseLed {
+    pub fn new(led: Led) -> Self {
+        Self {
+            led,
+            off_at_ms: None,
+        }
+    }
+
+    /// Lights the LED from `now_ms` for `duration_ms`, restarting the current pulse if any.
+    pub fn pulse(&mut self, now_ms: u32, duration_ms: u32) {
+        self.led.set(true);
+        self.off_at_ms = Some(now_ms.wrapping_add(duration_ms));
+    }
+
+    /// Switches the LED off if its pulse is over at `now_ms`.
+    pub fn tick(&mut self, now_ms: u32) {
+        if let Some(off_at_ms) = self.off_at_ms {
+            // Wrapping difference, the pulses are much shorter than the timer period
+            if (now_ms.wrapping_sub(off_at_ms) as i32) >= 0 {
+                self.led.set(false);
+                self.off_at_ms = None;
+            }
+        }
+    }
+}
@@ -1,7 +1,21 @@
 #![no_std]
 
+mod boot;
+mod display;
 mod display_lcd_lcm2004;
+#[cfg(feature = "ssd1306")]
+mod display_oled_ssd1306;
+mod flash_store;
+mod led;
+mod panel;
 mod serial_write;
 
+pub use boot::*;
+pub use display::*;
 pub use display_lcd_lcm2004::*;
+#[cfg(feature = "ssd1306")]
+pub use display_oled_ssd1306::*;
+pub use flash_store::*;
+pub use led::*;
+pub use panel::*;
 pub use serial_write::*;
@@ -0,0 +1,135 @@
+use stm32f4xx_hal::{
+    gpio::{Edge, ErasedPin, ExtiPin, Input},
+    hal_02::Qei as _,
+    pac::{EXTI, TIM3},
+    qei::Qei,
+    syscfg::SysCfg,
+};
+
+/// Identifier of the encoder of the front panel.
+pub const MAIN_ENCODER: u8 = 0;
+/// Identifier of the push-button of the main encoder.
+pub const ENCODER_BUTTON: u8 = 0;
+/// Identifiers of the transport buttons.
+pub const PLAY_BUTTON: u8 = 1;
+pub const STOP_BUTTON: u8 = 2;
+pub const CONTINUE_BUTTON: u8 = 3;
+
+// Counts produced by the encoder for each detent
+const COUNTS_PER_DETENT: i16 = 4;
+// Minimum time between two changes of a button state
+const DEBOUNCE_MS: u32 = 20;
+const MAX_BUTTONS: usize = 8;
+
+/// Event produced by a control of the front panel.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PanelEvent {
+    /// Encoder `id` turned by `delta` detents, positive values are clockwise.
+    EncoderTurn { id: u8, delta: i8 },
+    /// Button `id` was pushed.
+    ButtonPress { id: u8 },
+    /// Button `id` was released.
+    ButtonRelease { id: u8 },
+}
+
+/// Quadrature rotary encoder read by TIM3 in encoder mode.
+pub struct Encoder {
+    qei: Qei<TIM3>,
+    id: u8,
+    count: u16,
+}
+
+impl Encoder {
+    pub fn new(qei: Qei<TIM3>, id: u8) -> Self {
+        let count = qei.count();
+        Self { qei, id, count }
+    }
+
+    /// Returns the rotation since the last call, if any.
+    pub fn poll(&mut self) -> Option<PanelEvent> {
+        let diff = self.qei.count().wrapping_sub(self.count) as i16;
+        let delta = diff / COUNTS_PER_DETENT;
+        if delta == 0 {
+            return None;
+        }
+        // Keep the counts of an incomplete detent for the next call
+        self.count = self.count.wrapping_add((delta * COUNTS_PER_DETENT) as u16);
+        Some(PanelEvent::EncoderTurn {
+            id: self.id,
+            delta: delta.clamp(i8::MIN as i16, i8::MAX as i16) as i8,
+        })
+    }
+}
+
+/// Push-button between a pin and the ground, the pin must use its pull-up.
+/// Each change of state raises an EXTI interrupt.
+pub struct Button {
+    pin: ErasedPin<Input>,
+    id: u8,
+    pressed: bool,
+    last_change_ms: u32,
+}
+
+impl Button {
+    pub fn new(mut pin: ErasedPin<Input>, id: u8, syscfg: &mut SysCfg, exti: &mut EXTI) -> Self {
+        pin.make_interrupt_source(syscfg);
+        pin.trigger_on_edge(exti, Edge::RisingFalling);
+        pin.enable_interrupt(exti);
+        Self {
+            pin,
+            id,
+            pressed: false,
+            last_change_ms: 0,
+        }
+    }
+
+    /// Returns the debounced change of state at time `now_ms`, if any.
+    pub fn poll(&mut self, now_ms: u32) -> Option<PanelEvent> {
+        let pressed = self.pin.is_low();
+        if pressed == self.pressed || now_ms.wrapping_sub(self.last_change_ms) < DEBOUNCE_MS {
+            return None;
+        }
+        self.pressed = pressed;
+        self.last_change_ms = now_ms;
+        let id = self.id;
+        Some(if pressed {
+            PanelEvent::ButtonPress { id }
+        } else {
+            PanelEvent::ButtonRelease { id }
+        })
+    }
+}
+
+/// Controls of the front panel.
+pub struct Panel {
+    encoder: Encoder,
+    buttons: heapless::Vec<Button, MAX_BUTTONS>,
+}
+
+impl Panel {
+    pub fn new(encoder: Encoder, buttons: impl IntoIterator<Item = Button>) -> Self {
+        Self {
+            encoder,
+            buttons: buttons.into_iter().collect(),
+        }
+    }
+
+    /// Clears the pending EXTI interrupts of the buttons.
+    pub fn clear_interrupts(&mut self) {
+        self.buttons
+            .iter_mut()
+            .filter(|b| b.pin.check_interrupt())
+            .for_each(|b| b.pin.clear_interrupt_pending_bit());
+    }
+
+    /// Calls `f` for each event since the last call. Buttons are debounced at time `now_ms`.
+    pub fn poll(&mut self, now_ms: u32, mut f: impl FnMut(PanelEvent)) {
+        if let Some(event) = self.encoder.poll() {
+            f(event);
+        }
+        self.buttons
+            .iter_mut()
+            .filter_map(|b| b.poll(now_ms))
+            .for_each(f);
+    }
+}
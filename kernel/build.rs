@@ -10,11 +10,66 @@
 use mseq_core::Track;
 use mseq_tracks::index::load_from_file;
 use postcard::to_stdvec;
+use serde::Deserialize;
 use std::env;
-use std::fs::{File, create_dir, remove_dir_all};
+use std::fs::{File, create_dir, read_to_string, remove_dir_all};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
+// Track played by the conductor besides the tracks of the index, it can be part of the scenes
+const DEMO_TRACK: &str = "demo";
+// Tracks with a channel setting, `user::settings::MAX_TRACKS`, including the demo and the recorded
+// tracks
+const MAX_TRACKS: usize = 16;
+
+// Set of tracks played together, selected by a Program Change
+#[derive(Deserialize)]
+struct Scene {
+    name: String,
+    tracks: Vec<String>,
+}
+
+// Scene played for a number of bars in the song
+#[derive(Deserialize)]
+struct Part {
+    scene: String,
+    bars: u32,
+}
+
+// Arrangement of scenes, loops from `loop_end` back to `loop_start` (indices of parts)
+#[derive(Deserialize, Default)]
+struct Song {
+    #[serde(default)]
+    part: Vec<Part>,
+    loop_start: Option<usize>,
+    loop_end: Option<usize>,
+}
+
+// Fields of a track of the index shared by every kind
+#[derive(Deserialize)]
+struct TrackEntry {
+    name: String,
+    channel: u8,
+}
+
+// Parts of the index needed by the generated tables.
+// The kinds are in the order of the tracks returned by `load_from_file`.
+#[derive(Deserialize)]
+struct Index {
+    #[serde(default)]
+    acid: Vec<TrackEntry>,
+    #[serde(default)]
+    arp: Vec<TrackEntry>,
+    #[serde(default)]
+    div: Vec<TrackEntry>,
+    #[serde(default)]
+    midi: Vec<TrackEntry>,
+    #[serde(default)]
+    scene: Vec<Scene>,
+    #[serde(default)]
+    song: Song,
+}
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
@@ -34,15 +89,11 @@ fn main() {
     let tracks = load_from_file("../res/index.toml").unwrap();
     println!("cargo:rerun-if-changed=../res/index.toml");
 
-    if !tracks.is_empty() {
-        let path = Path::new("../track_bin");
-
-        if path.exists() {
-            remove_dir_all(path).unwrap(); // Removes the directory and all its contents
-        }
-
-        create_dir(path).unwrap();
+    let path = Path::new("../track_bin");
+    if path.exists() {
+        remove_dir_all(path).unwrap(); // Removes the directory and all its contents
     }
+    create_dir(path).unwrap();
 
     for (t, n) in tracks {
         let bytes = to_stdvec(&t).unwrap();
@@ -51,5 +102,97 @@ fn main() {
         println!("cargo:rerun-if-changed={}", n.display());
     }
 
+    let index = read_to_string("../res/index.toml").unwrap();
+    let index: Index = toml::from_str(&index).unwrap();
+    write_tracks(&index);
+    write_scenes(&index);
+    write_song(&index.song, &index.scene);
+
     println!("cargo:rerun-if-changed=build.rs");
 }
+
+// Generates the table of tracks included by the user crate
+fn write_tracks(index: &Index) {
+    let bin_dir = Path::new("../track_bin").canonicalize().unwrap();
+    let mut file = File::create(bin_dir.join("tracks.rs")).unwrap();
+    writeln!(file, "// Generated from res/index.toml by kernel/build.rs").unwrap();
+    writeln!(file, "pub const TRACKS: &[TrackEntry] = &[").unwrap();
+    let kinds = [
+        ("Acid", &index.acid),
+        ("Arp", &index.arp),
+        ("Div", &index.div),
+        ("Midi", &index.midi),
+    ];
+    let count: usize = kinds.iter().map(|(_, tracks)| tracks.len()).sum();
+    assert!(
+        count + 2 <= MAX_TRACKS,
+        "{count} tracks in the index, {} at most",
+        MAX_TRACKS - 2
+    );
+    for (kind, tracks) in kinds {
+        for track in tracks {
+            let bin = bin_dir.join(format!("{}.bin", track.name));
+            writeln!(
+                file,
+                "    TrackEntry {{ name: {:?}, channel: {}, kind: TrackKind::{kind}, bytes: include_bytes!({:?}) }},",
+                track.name,
+                track.channel,
+                bin.display().to_string()
+            )
+            .unwrap();
+        }
+    }
+    writeln!(file, "];").unwrap();
+}
+
+// Generates the table of scenes included by the user crate
+fn write_scenes(index: &Index) {
+    let names: Vec<&str> = [&index.acid, &index.arp, &index.div, &index.midi]
+        .into_iter()
+        .flatten()
+        .map(|track| track.name.as_str())
+        .chain([DEMO_TRACK])
+        .collect();
+    let mut file = File::create("../track_bin/scenes.rs").unwrap();
+    writeln!(file, "// Generated from res/index.toml by kernel/build.rs").unwrap();
+    writeln!(file, "pub const SCENES: &[Scene] = &[").unwrap();
+    for scene in &index.scene {
+        if let Some(track) = scene.tracks.iter().find(|t| !names.contains(&t.as_str())) {
+            panic!("Unknown track {track} in the scene {}", scene.name);
+        }
+        writeln!(
+            file,
+            "    Scene {{ name: {:?}, tracks: &{:?} }},",
+            scene.name, scene.tracks
+        )
+        .unwrap();
+    }
+    writeln!(file, "];").unwrap();
+}
+
+// Generates the song included by the user crate, the scenes are referenced by index
+fn write_song(song: &Song, scenes: &[Scene]) {
+    let mut file = File::create("../track_bin/song.rs").unwrap();
+    writeln!(file, "// Generated from res/index.toml by kernel/build.rs").unwrap();
+    writeln!(file, "pub const SONG: Song = Song {{").unwrap();
+    writeln!(file, "    parts: &[").unwrap();
+    for part in &song.part {
+        let scene = scenes
+            .iter()
+            .position(|s| s.name == part.scene)
+            .unwrap_or_else(|| panic!("Unknown scene {} in the song", part.scene));
+        writeln!(
+            file,
+            "        Part {{ scene: {scene}, bars: {} }},",
+            part.bars.max(1)
+        )
+        .unwrap();
+    }
+    writeln!(file, "    ],").unwrap();
+    let last = song.part.len().saturating_sub(1);
+    let loop_end = song.loop_end.unwrap_or(last).min(last);
+    let loop_start = song.loop_start.unwrap_or(0).min(loop_end);
+    writeln!(file, "    loop_start: {loop_start},").unwrap();
+    writeln!(file, "    loop_end: {loop_end},").unwrap();
+    writeln!(file, "}};").unwrap();
+}
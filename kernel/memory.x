@@ -1,7 +1,9 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K 
-  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+  /* Sectors 4 and 5, after the bootloader and the settings */
+  FLASH : ORIGIN = 0x08010000, LENGTH = 192K
+  /* The last 8 bytes hold the update requests to the bootloader */
+  RAM : ORIGIN = 0x20000000, LENGTH = 131064
 }
 
 _stack_start = ORIGIN(RAM) + LENGTH(RAM);
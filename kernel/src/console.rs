@@ -0,0 +1,215 @@
+//! Command shell of the USB serial console, enabled by the `usb` feature.
+//!
+//! Open the serial port of the device with any terminal (e.g. `picocom /dev/ttyACM0`) and type
+//! `help` for the list of commands. The logs are mirrored on the console from the level set by the
+//! `log` command.
+
+use alloc::string::String;
+use core::fmt::Write;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use log::{Level, LevelFilter};
+use mseq_core::Context;
+use thiserror::Error;
+use user::conductor::UserConductor;
+use user::pages::Diagnostics;
+use user::settings::Setting;
+
+use crate::{IS_PLAYING, heap, rtt_logger, usb};
+
+const LINE_LEN: usize = 64;
+// A log line longer than this is truncated
+const LOG_LINE_LEN: usize = 128;
+const PROMPT: &str = "> ";
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+const HELP: &str = "\
+status         transport and clock\r
+bpm <bpm>      set the tempo\r
+tracks         channel and mixer state of the tracks\r
+log [<level>]  level of the logs mirrored here: off, error, warn, info, debug or trace\r
+stats          inputs and heap usage\r
+";
+
+// Level of the logs mirrored on the console, as a `LevelFilter`
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LevelFilter::Info as u8);
+
+#[derive(Error, Debug)]
+pub enum CommandError {
+    #[error("Unknown command: {0}, type help for the list of commands")]
+    Unknown(String),
+    #[error("Invalid argument for {0}")]
+    Argument(&'static str),
+}
+
+/// Command typed on the console.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Command {
+    Help,
+    Status,
+    Bpm(u8),
+    Tracks,
+    /// Shows the log level without argument.
+    Log(Option<LevelFilter>),
+    Stats,
+}
+
+impl Command {
+    /// Parses a line of the console, returns `None` for an empty line.
+    pub fn parse(line: &str) -> Result<Option<Self>, CommandError> {
+        let mut words = line.split_whitespace();
+        let Some(name) = words.next() else {
+            return Ok(None);
+        };
+        let arg = words.next();
+        let command = match name {
+            "help" => Command::Help,
+            "status" => Command::Status,
+            "bpm" => Command::Bpm(parse_arg(arg, "bpm")?),
+            "tracks" => Command::Tracks,
+            "log" => Command::Log(arg.map(|a| parse_arg(Some(a), "log")).transpose()?),
+            "stats" => Command::Stats,
+            _ => return Err(CommandError::Unknown(name.into())),
+        };
+        Ok(Some(command))
+    }
+
+    /// Runs the command and returns its output.
+    /// Changes made to `context` are applied at the next clock tick.
+    pub fn run(
+        self,
+        conductor: &mut UserConductor,
+        context: &mut Context,
+        diagnostics: &Diagnostics,
+    ) -> String {
+        let mut out = String::new();
+        // Writing to a `String` does not fail
+        let _ = match self {
+            Command::Help => write!(out, "{HELP}"),
+            Command::Status => {
+                let settings = conductor.settings();
+                write!(
+                    out,
+                    "{} step {} bpm {} clock {:?}\r\n",
+                    // `Context::is_running` stays true until the sequencer quits
+                    if IS_PLAYING.load(Ordering::Relaxed) {
+                        "playing"
+                    } else {
+                        "stopped"
+                    },
+                    context.get_step(),
+                    context.get_bpm(),
+                    settings.clock_mode,
+                )
+            }
+            Command::Bpm(bpm) => {
+                conductor.settings_mut().set(Setting::Bpm, bpm);
+                conductor.apply_setting(Setting::Bpm, context);
+                write!(out, "bpm {}\r\n", conductor.settings().bpm)
+            }
+            Command::Tracks => {
+                conductor
+                    .track_names()
+                    .iter()
+                    .enumerate()
+                    .try_for_each(|(i, name)| {
+                        let channel = conductor.settings().get(Setting::TrackChannel(i));
+                        write!(out, "{i:2} {name:16} ")?;
+                        if channel == 0 {
+                            write!(out, "ch -")?;
+                        } else {
+                            write!(out, "ch {channel:2}")?;
+                        }
+                        write!(out, " {:?}\r\n", conductor.mixer().state(i))
+                    })
+            }
+            Command::Log(level) => {
+                if let Some(level) = level {
+                    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+                    rtt_logger::update_max_level();
+                }
+                write!(out, "log {}\r\n", log_level())
+            }
+            Command::Stats => {
+                let (heap_used, heap_free) = heap::heap_stats();
+                write!(
+                    out,
+                    "inputs {}\r\nheap {heap_used} used {heap_free} free\r\n",
+                    diagnostics.inputs
+                )
+            }
+        };
+        out
+    }
+}
+
+fn parse_arg<T: FromStr>(arg: Option<&str>, command: &'static str) -> Result<T, CommandError> {
+    arg.and_then(|a| a.parse().ok())
+        .ok_or(CommandError::Argument(command))
+}
+
+/// Line being typed on the console, echoed as the terminal does not echo it.
+pub struct LineEditor {
+    line: heapless::String<LINE_LEN>,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self {
+            line: heapless::String::new(),
+        }
+    }
+
+    /// Handles a byte typed on the console, returns the line once it is entered.
+    pub fn input(&mut self, byte: u8) -> Option<heapless::String<LINE_LEN>> {
+        match byte {
+            b'\r' | b'\n' => {
+                usb::write_console(b"\r\n");
+                return Some(core::mem::take(&mut self.line));
+            }
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() {
+                    usb::write_console(b"\x08 \x08");
+                }
+            }
+            // The other control characters are ignored, like the characters beyond the line
+            b' '..=b'~' => {
+                if self.line.push(byte as char).is_ok() {
+                    usb::write_console(&[byte]);
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+pub fn prompt() {
+    usb::write_console(PROMPT.as_bytes());
+}
+
+/// Level of the logs mirrored on the console.
+pub fn log_level() -> LevelFilter {
+    match LOG_LEVEL.load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Mirrors a log on the console if it is enabled at the console level.
+pub fn log(level: Level, target: &str, message: core::fmt::Arguments) {
+    if level > log_level() {
+        return;
+    }
+    let mut line = heapless::String::<LOG_LINE_LEN>::new();
+    // Errors only mean the line was truncated
+    let _ = write!(line, "[{level}] {target} - {message}");
+    usb::write_console(line.as_bytes());
+    usb::write_console(b"\r\n");
+}
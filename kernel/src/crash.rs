@@ -0,0 +1,157 @@
+//! Panic and HardFault handlers.
+//!
+//! A fatal error releases every note on the MIDI output, prints the error on RTT and on the
+//! screen, and stores it in RAM that is not cleared by a reset so that it can be reported at the
+//! next boot.
+
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cortex_m_rt::{ExceptionFrame, exception};
+use driver::{Display, DisplayText};
+use rtt_target::rprintln;
+use stm32f4xx_hal::{pac, prelude::*};
+
+use crate::midi_connection::{ALL_NOTES_OFF, CC};
+use crate::{rtt_logger, screen};
+
+const RECORD_MAGIC: u32 = 0x6d73_6571;
+const MESSAGE_LEN: usize = 64;
+// Fits on every supported screen, the text cannot scroll once crashed
+const SCREEN_WIDTH: usize = 16;
+
+pub type CrashReport = heapless::String<MESSAGE_LEN>;
+
+#[repr(C)]
+struct CrashRecord {
+    magic: u32,
+    len: u32,
+    message: [u8; MESSAGE_LEN],
+}
+
+impl Write for CrashRecord {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let start = self.len as usize;
+        let end = (start + s.len()).min(MESSAGE_LEN);
+        self.message[start..end].copy_from_slice(&s.as_bytes()[..end - start]);
+        self.len = end as u32;
+        Ok(())
+    }
+}
+
+// `.uninit` is neither zeroed nor initialized at startup
+#[unsafe(link_section = ".uninit.CRASH_RECORD")]
+static mut CRASH_RECORD: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+static CRASHED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the crash stored by the previous run, if any, and clears it.
+pub fn take_crash_report() -> Option<CrashReport> {
+    let record = (&raw mut CRASH_RECORD).cast::<CrashRecord>();
+    let record = unsafe {
+        // The record is only valid if the magic number was written by `fatal`
+        if core::ptr::read_volatile(&raw const (*record).magic) != RECORD_MAGIC {
+            return None;
+        }
+        &mut *record
+    };
+    record.magic = 0;
+    let message = &record.message[..(record.len as usize).min(MESSAGE_LEN)];
+    // The message might have been truncated in the middle of a character
+    let message = match core::str::from_utf8(message) {
+        Ok(m) => m,
+        Err(e) => core::str::from_utf8(&message[..e.valid_up_to()]).unwrap_or_default(),
+    };
+    CrashReport::try_from(message).ok()
+}
+
+/// Formats a crash report to be shown on the screen.
+pub fn crash_text(report: &str) -> DisplayText {
+    let mut text = DisplayText::default();
+    text.lines[0] = heapless::String::try_from("!! CRASH !!").unwrap();
+    let mut chars = report.chars().filter(|c| !c.is_control());
+    text.lines[1..].iter_mut().for_each(|line| {
+        chars
+            .by_ref()
+            .take(SCREEN_WIDTH)
+            .for_each(|c| line.push(c).unwrap());
+    });
+    text
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    match info.location() {
+        Some(location) => fatal(format_args!(
+            "{}:{} {}",
+            location.file(),
+            location.line(),
+            info.message()
+        )),
+        None => fatal(format_args!("{}", info.message())),
+    }
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    fatal(format_args!("HardFault pc={:#010x}", frame.pc()))
+}
+
+fn fatal(args: core::fmt::Arguments) -> ! {
+    cortex_m::interrupt::disable();
+
+    // Do not try again if the crash handling crashed itself
+    if !CRASHED.swap(true, Ordering::Relaxed) {
+        // The logs leading to the crash come first
+        rtt_logger::drain();
+        rprintln!("\x1B[31m[FATAL]\x1B[0m {}", args);
+        all_notes_off();
+
+        let record = (&raw mut CRASH_RECORD).cast::<CrashRecord>();
+        let record = unsafe {
+            record.write(CrashRecord {
+                magic: 0,
+                len: 0,
+                message: [0; MESSAGE_LEN],
+            });
+            &mut *record
+        };
+        let _ = record.write_fmt(args);
+        record.magic = RECORD_MAGIC;
+
+        let message = core::str::from_utf8(&record.message[..record.len as usize]);
+        show_on_screen(message.unwrap_or("Unknown error"));
+    }
+
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
+/// Sends an all notes off message on every channel, bypassing the serial driver which might be
+/// in use.
+fn all_notes_off() {
+    let usart = unsafe { &*pac::USART1::ptr() };
+    if usart.cr1().read().ue().bit_is_clear() {
+        return;
+    }
+    (0..16).for_each(|channel| {
+        [CC | channel, ALL_NOTES_OFF, 0].iter().for_each(|&b| {
+            while usart.sr().read().txe().bit_is_clear() {}
+            usart.dr().write(|w| w.dr().set(b as u16));
+        })
+    });
+}
+
+fn show_on_screen(report: &str) {
+    // Peripherals are reinitialized from scratch as the crash might have happened while the screen
+    // was in use.
+    let dp = unsafe { pac::Peripherals::steal() };
+    let clocks = dp.RCC.constrain().cfgr.use_hse(25.MHz()).freeze();
+    let gpiob = dp.GPIOB.split();
+    if let Some(mut display) = screen::init(dp.I2C1, gpiob.pb6, gpiob.pb7, dp.TIM5, &clocks) {
+        display.update(&crash_text(report));
+    }
+}
@@ -0,0 +1,44 @@
+//! State of the sequencer sent to the host over SysEx.
+
+use alloc::vec::Vec;
+
+use sysex::Dump;
+use user::conductor::UserConductor;
+
+use crate::heap;
+
+/// Firmware version reported by the Identity Reply.
+pub const VERSION: [u8; 4] = [
+    parse_version(env!("CARGO_PKG_VERSION_MAJOR")),
+    parse_version(env!("CARGO_PKG_VERSION_MINOR")),
+    parse_version(env!("CARGO_PKG_VERSION_PATCH")),
+    0,
+];
+
+/// Returns the data of `dump`, in the format described by [`Dump`].
+pub fn dump(dump: Dump, conductor: &UserConductor) -> Vec<u8> {
+    match dump {
+        Dump::Settings => conductor.settings().to_bytes(),
+        Dump::Tracks => conductor
+            .track_names()
+            .iter()
+            .flat_map(|name| name.bytes().chain(core::iter::once(0)))
+            .collect(),
+        Dump::Version => env!("CARGO_PKG_VERSION").as_bytes().to_vec(),
+        Dump::Heap => {
+            let (used, free) = heap::heap_stats();
+            [used as u32, free as u32]
+                .iter()
+                .flat_map(|n| n.to_le_bytes())
+                .collect()
+        }
+    }
+}
+
+// Each part of the version is sent in a 7-bit byte
+const fn parse_version(part: &str) -> u8 {
+    match u8::from_str_radix(part, 10) {
+        Ok(n) if n < 0x80 => n,
+        _ => panic!("Version part out of range"),
+    }
+}
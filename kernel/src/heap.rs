@@ -1,7 +1,8 @@
 use core::mem::MaybeUninit;
 use embedded_alloc::LlffHeap as Heap;
 
-const HEAP_SIZE: usize = 1024;
+// Holds the tracks of the index once deserialized
+const HEAP_SIZE: usize = 16 * 1024;
 
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
@@ -10,3 +11,8 @@ pub fn allocator_init() {
     static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
     unsafe { HEAP.init(&raw mut HEAP_MEM as usize, HEAP_SIZE) }
 }
+
+/// Returns the number of bytes currently used and free in the heap.
+pub fn heap_stats() -> (usize, usize) {
+    (HEAP.used(), HEAP.free())
+}
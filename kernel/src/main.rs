@@ -2,12 +2,25 @@
 #![no_std]
 
 extern crate alloc;
+#[cfg(feature = "usb")]
+mod console;
+mod crash;
+mod dump;
 mod heap;
 mod midi_connection;
 mod midi_input;
 mod rtt_logger;
+mod screen;
+mod status_leds;
+#[cfg(feature = "usb")]
+mod usb;
 
-use panic_rtt_target as _;
+use core::sync::atomic::AtomicBool;
+
+// Clock mode chosen in the settings, read by the interrupts of both clock sources
+static IS_MASTER: AtomicBool = AtomicBool::new(true);
+// Set by the clock while the sequencer plays, the flash is not written meanwhile
+static IS_PLAYING: AtomicBool = AtomicBool::new(false);
 
 #[rtic::app(
     device = stm32f4xx_hal::pac,
@@ -18,19 +31,25 @@ use panic_rtt_target as _;
 )]
 
 mod app {
-    use log::{debug, error, info, trace, warn};
+    #[cfg(feature = "usb")]
+    use alloc::{format, string::String};
+    use core::sync::atomic::Ordering;
+
+    use log::{Level, debug, error, info, trace, warn};
     use mseq_core::MidiMessage;
     use mseq_core::*;
+    use rtic::Mutex;
     use rtic::mutex_prelude::TupleExt02;
     use rtic::mutex_prelude::TupleExt03;
     use rtic_monotonics::systick::prelude::*;
     use rtic_sync::{
         make_signal,
-        signal::{Signal, SignalReader, SignalWriter},
+        signal::{SignalReader, SignalWriter},
     };
     use stm32f4xx_hal::{
         pac::USART1,
         prelude::*,
+        qei::QeiExt,
         rtc::Rtc,
         serial::{
             Config, Rx, Serial,
@@ -39,22 +58,77 @@ mod app {
     };
 
     use crate::app::shared_resources::*;
-    use crate::midi_connection::MidiOut;
-    use crate::midi_input::MidiInputHandler;
+    #[cfg(feature = "usb")]
+    use crate::console::{self, Command, LineEditor};
+    use crate::crash;
+    use crate::deferred;
+    use crate::dump;
+    use crate::midi_connection::{MidiOut, send_cc, send_sysex};
+    use crate::midi_input::{MidiInputHandler, Port, SysEx};
     use crate::rtt_logger;
-    use crate::{heap, rtt_logger::RttLogger};
+    use crate::screen;
+    use crate::status_leds::{ACTIVITY, ActivityLeds, StatusLeds};
+    #[cfg(feature = "usb")]
+    use crate::usb;
+    use crate::{IS_MASTER, IS_PLAYING, heap};
+    use driver::{Display, FlashStore, PanelEvent, Store};
+    #[cfg(feature = "usb")]
+    use rtic_sync::{channel, make_channel};
+    #[cfg(feature = "usb")]
+    use stm32f4xx_hal::pac::Interrupt;
+    use sysex::{Message, Nak, ParseError, Receiver, identity_reply};
     use user::conductor;
+    use user::menu::{MAIN_MENU, Menu, MenuAction, MenuInput};
+    use user::nrpn::NrpnDecoder;
+    use user::pages::{Diagnostics, REFRESH_POLICY};
+    use user::settings::ClockMode;
+
+    // Period at which the front panel is read when no button interrupt occurs
+    const PANEL_POLL_PERIOD_MS: u32 = 10;
+    const PANEL_QUEUE_SIZE: usize = 16;
+    const SYSEX_QUEUE_SIZE: usize = 2;
+    #[cfg(feature = "usb")]
+    const CONSOLE_QUEUE_SIZE: usize = 128;
+    // Period at which the activity LEDs are updated
+    const LED_PERIOD_MS: u32 = 10;
+    // Steps of a quarter note and of a bar
+    const BEAT_STEPS: u32 = 24;
+    const BAR_STEPS: u32 = 96;
+
+    // Period at which the changes of the NRPN parameters are echoed
+    const ECHO_PERIOD_MS: u32 = 20;
+
+    // Period at which the log levels received on the RTT down channel are applied
+    const LOG_COMMAND_PERIOD_MS: u32 = 100;
 
-    //TODO: understand and add comment
+    // Period at which the changes of the settings are saved to the flash, while the sequencer is
+    // stopped
+    const SAVE_PERIOD_MS: u32 = 2000;
+
+    // Holding the encoder button longer opens or closes the menu
+    const LONG_PRESS_MS: u32 = 800;
+
+    // Time during which the crash of the previous run is shown on the screen
+    const CRASH_REPORT_DURATION_S: u32 = 3;
+
+    // Monotonic timer based on the SysTick with a 100 Hz tick rate.
+    // Schedules the tasks that are not bound to the musical clock (e.g. display refresh).
     systick_monotonic!(Mono, 100);
 
     #[shared]
     struct Shared {
         conductor: conductor::UserConductor,
         input_queue: InputQueue,
+        panel: driver::Panel,
+        // Panel events with the time they were read at, in ms
+        panel_queue: heapless::Deque<(u32, PanelEvent), PANEL_QUEUE_SIZE>,
+        // SysEx messages with the input they were received from
+        sysex_queue: heapless::Deque<(Port, SysEx), SYSEX_QUEUE_SIZE>,
         midi_controller: MidiController<MidiOut>,
         mseq_ctx: mseq_core::Context,
-        display_text: driver::DisplayText,
+        diagnostics: Diagnostics,
+        menu: Menu,
+        status_leds: StatusLeds,
     }
 
     #[local]
@@ -64,25 +138,66 @@ mod app {
         clock_period: u32,
         midi_input_handler: MidiInputHandler,
         input_signal_writer: SignalWriter<'static, ()>,
-        display: Option<driver::Lcd>,
-        is_master: bool,
+        sysex_signal_writer: SignalWriter<'static, ()>,
+        #[cfg(feature = "usb")]
+        usb_input_handler: MidiInputHandler,
+        #[cfg(feature = "usb")]
+        usb_input_signal_writer: SignalWriter<'static, ()>,
+        #[cfg(feature = "usb")]
+        usb_sysex_signal_writer: SignalWriter<'static, ()>,
+        #[cfg(feature = "usb")]
+        console_sender: channel::Sender<'static, u8, CONSOLE_QUEUE_SIZE>,
+        #[cfg(feature = "usb")]
+        console_receiver: channel::Receiver<'static, u8, CONSOLE_QUEUE_SIZE>,
+        #[cfg(feature = "usb")]
+        line_editor: LineEditor,
+        panel_int_signal_writer: SignalWriter<'static, ()>,
+        panel_poll_signal_writer: SignalWriter<'static, ()>,
+        refresh_signal_writer: SignalWriter<'static, ()>,
+        panel_refresh_signal_writer: SignalWriter<'static, ()>,
+        display: Option<driver::Screen>,
+        display_text: driver::DisplayText,
+        crash_text: Option<driver::DisplayText>,
+        activity_leds: ActivityLeds,
+        store: Option<FlashStore>,
+        receiver: Receiver,
+    }
+
+    // What an event of the front panel does
+    enum PanelAction {
+        Forward(PanelEvent),
+        Menu(MenuInput),
+        ToggleMenu,
+        Transport(u8),
     }
 
-    #[init(local = [logger: RttLogger = RttLogger {level: log::LevelFilter::Off} ])]
+    #[init]
     fn init(mut cx: init::Context) -> (Shared, Local) {
-        rtt_logger::RttLogger::init(cx.local.logger, log::LevelFilter::Trace);
+        // The levels can be changed from the RTT down channel, see `rtt_logger`
+        rtt_logger::init(log::LevelFilter::Info);
         trace!("Init");
 
         // Initilialize allocator
         heap::allocator_init();
 
+        // Report the crash of the previous run
+        let crash_report = crash::take_crash_report();
+        if let Some(report) = &crash_report {
+            error!("Previous run crashed: {report}");
+        }
+
         // GPIO
         let gpioa = cx.device.GPIOA.split();
         let gpiob = cx.device.GPIOB.split();
 
         // Serial connection
         let rcc = cx.device.RCC.constrain();
-        let clocks = rcc.cfgr.use_hse(25.MHz()).freeze();
+        let cfgr = rcc.cfgr.use_hse(25.MHz());
+        // The USB peripheral needs the 48 MHz clock of the PLL
+        #[cfg(feature = "usb")]
+        let cfgr = cfgr.sysclk(48.MHz()).require_pll48clk();
+        let clocks = cfgr.freeze();
+        Mono::start(cx.core.SYST, clocks.sysclk().raw());
         let rx_1 = gpiob.pb3.into_alternate();
         let tx_1 = gpioa.pa15.into_alternate();
         let pa1 = gpioa.pa1.into_floating_input();
@@ -108,17 +223,70 @@ mod app {
         let (tx, mut rx) = serial.split();
         rx.listen();
 
-        // lcd screen
-        let i2c = stm32f4xx_hal::i2c::I2c::new(
+        // USB midi port and console
+        #[cfg(feature = "usb")]
+        usb::init(stm32f4xx_hal::otg_fs::USB::new(
+            (
+                cx.device.OTG_FS_GLOBAL,
+                cx.device.OTG_FS_DEVICE,
+                cx.device.OTG_FS_PWRCLK,
+            ),
+            (gpioa.pa11, gpioa.pa12),
+            &clocks,
+        ));
+
+        // screen
+        let display = screen::init(
             cx.device.I2C1,
-            (gpiob.pb6, gpiob.pb7),
-            stm32f4xx_hal::i2c::Mode::standard(50.kHz()),
+            gpiob.pb6,
+            gpiob.pb7,
+            cx.device.TIM5,
             &clocks,
         );
-        let delay = cx.device.TIM3.delay_us(&clocks);
-        let display = driver::Lcd::new(i2c, delay);
         //let display = None;
 
+        // Front panel
+        let mut syscfg = cx.device.SYSCFG.constrain();
+        let encoder = driver::Encoder::new(
+            // The pull-ups are kept when the pins are switched to the timer
+            cx.device.TIM3.qei((
+                gpiob.pb4.into_pull_up_input(),
+                gpiob.pb5.into_pull_up_input(),
+            )),
+            driver::MAIN_ENCODER,
+        );
+        let encoder_button = driver::Button::new(
+            gpiob.pb12.into_pull_up_input().erase(),
+            driver::ENCODER_BUTTON,
+            &mut syscfg,
+            &mut cx.device.EXTI,
+        );
+        let transport_buttons = [
+            (gpiob.pb13.into_pull_up_input().erase(), driver::PLAY_BUTTON),
+            (gpiob.pb14.into_pull_up_input().erase(), driver::STOP_BUTTON),
+            (
+                gpiob.pb15.into_pull_up_input().erase(),
+                driver::CONTINUE_BUTTON,
+            ),
+        ]
+        .map(|(pin, id)| driver::Button::new(pin, id, &mut syscfg, &mut cx.device.EXTI));
+        let panel = driver::Panel::new(
+            encoder,
+            core::iter::once(encoder_button).chain(transport_buttons),
+        );
+
+        // Status LEDs
+        let status_leds = StatusLeds::new(
+            driver::Led::new(gpioa.pa4.into_push_pull_output().erase()),
+            driver::Led::new(gpioa.pa5.into_push_pull_output().erase()),
+        );
+        let activity_leds = ActivityLeds::new(
+            driver::Led::new(gpioa.pa6.into_push_pull_output().erase()),
+            driver::Led::new(gpioa.pa7.into_push_pull_output().erase()),
+            driver::Led::new(gpiob.pb0.into_push_pull_output().erase()),
+        );
+        update_leds::spawn().unwrap();
+
         // MidiOut
         let midi_out = MidiOut::new(tx);
 
@@ -127,12 +295,32 @@ mod app {
         let mut mseq_ctx = mseq_core::Context::default();
 
         // Clock
+        // The clock mode can be changed from the menu, the internal clock always runs
+        conductor.settings_mut().clock_mode = if is_master {
+            ClockMode::Master
+        } else {
+            ClockMode::Slave
+        };
+
+        // Settings saved before the power cycle, the clock mode always comes from the switch
+        let store = FlashStore::new(cx.device.FLASH)
+            .inspect_err(|e| error!("Settings storage unavailable: {e}"))
+            .ok();
+        if let Some(store) = &store {
+            conductor.load(store, &mut mseq_ctx);
+        }
+        IS_MASTER.store(
+            conductor.settings().clock_mode == ClockMode::Master,
+            Ordering::Relaxed,
+        );
+        save_state::spawn().unwrap();
+        poll_log_commands::spawn().unwrap();
+        echo_nrpn::spawn().unwrap();
+
         let mut rtc = Rtc::new(cx.device.RTC, &mut cx.device.PWR);
         let clock_period = mseq_ctx.get_period_us() as u32;
-        if is_master {
-            rtc.enable_wakeup(clock_period.micros::<1, 1_000_000>().into());
-            rtc.listen(&mut cx.device.EXTI, stm32f4xx_hal::rtc::Event::Wakeup);
-        }
+        rtc.enable_wakeup(clock_period.micros::<1, 1_000_000>().into());
+        rtc.listen(&mut cx.device.EXTI, stm32f4xx_hal::rtc::Event::Wakeup);
 
         // Input Queue
         let input_queue = InputQueue::new();
@@ -141,6 +329,23 @@ mod app {
         let (w, r) = make_signal!(());
         handle_input::spawn(r).unwrap();
 
+        // SysEx Signal
+        let (sysex_w, sysex_r) = make_signal!(());
+        handle_sysex::spawn(sysex_r).unwrap();
+
+        // Front panel Signal
+        let (panel_w, panel_r) = make_signal!(());
+        panel_input::spawn(panel_r).unwrap();
+        poll_panel::spawn().unwrap();
+
+        // Display refresh
+        let (refresh_w, refresh_r) = make_signal!(());
+        update_display::spawn(refresh_r).unwrap();
+
+        // Console input
+        #[cfg(feature = "usb")]
+        let (console_sender, console_receiver) = make_channel!(u8, CONSOLE_QUEUE_SIZE);
+
         // Conductor Init
         mseq_ctx.init(&mut conductor, &mut midi_controller);
 
@@ -150,41 +355,72 @@ mod app {
             Shared {
                 conductor,
                 input_queue,
+                panel,
+                panel_queue: heapless::Deque::new(),
+                sysex_queue: heapless::Deque::new(),
                 midi_controller,
                 mseq_ctx,
-                display_text: driver::DisplayText::default(),
+                diagnostics: Diagnostics::default(),
+                menu: Menu::new(&MAIN_MENU),
+                status_leds,
             },
             Local {
                 rx,
                 rtc,
                 clock_period,
                 midi_input_handler: MidiInputHandler::new(),
+                #[cfg(feature = "usb")]
+                usb_input_handler: MidiInputHandler::new(),
+                #[cfg(feature = "usb")]
+                usb_input_signal_writer: w.clone(),
+                #[cfg(feature = "usb")]
+                usb_sysex_signal_writer: sysex_w.clone(),
+                #[cfg(feature = "usb")]
+                console_sender,
+                #[cfg(feature = "usb")]
+                console_receiver,
+                #[cfg(feature = "usb")]
+                line_editor: LineEditor::new(),
                 input_signal_writer: w,
+                sysex_signal_writer: sysex_w,
+                panel_int_signal_writer: panel_w.clone(),
+                panel_poll_signal_writer: panel_w,
+                panel_refresh_signal_writer: refresh_w.clone(),
+                refresh_signal_writer: refresh_w,
                 display,
-                is_master,
+                display_text: driver::DisplayText::default(),
+                crash_text: crash_report.map(|report| crash::crash_text(&report)),
+                activity_leds,
+                store,
+                // Uploaded tracks are persisted in the store
+                receiver: Receiver::new(driver::MAX_VALUE_LEN),
             },
         )
     }
 
+    // The logs are printed when no task runs
     #[idle]
     fn idle(_: idle::Context) -> ! {
         loop {
-            continue;
+            rtt_logger::drain();
         }
     }
 
-    #[task(binds = RTC_WKUP, priority = 3, local = [rtc, clock_period], shared = [conductor, midi_controller, mseq_ctx, display_text])]
+    #[task(binds = RTC_WKUP, priority = 3, local = [rtc, clock_period], shared = [conductor, midi_controller, mseq_ctx, status_leds])]
     fn master_clock(mut cx: master_clock::Context) {
         // Clear clock interrupt flag
         cx.local
             .rtc
             .clear_interrupt(stm32f4xx_hal::rtc::Event::Wakeup);
+        if !IS_MASTER.load(Ordering::Relaxed) {
+            return;
+        }
 
         clock(
             &mut cx.shared.mseq_ctx,
             &mut cx.shared.midi_controller,
             &mut cx.shared.conductor,
-            &mut cx.shared.display_text,
+            &mut cx.shared.status_leds,
         );
 
         // If clock changed, update callback timing
@@ -203,126 +439,352 @@ mod app {
         mut mseq_ctx: &mut mseq_ctx_that_needs_to_be_locked,
         mut midi_controller: &mut midi_controller_that_needs_to_be_locked,
         mut conductor: &mut conductor_that_needs_to_be_locked,
-        mut display_text: &mut display_text_that_needs_to_be_locked,
+        status_leds: &mut status_leds_that_needs_to_be_locked,
     ) {
         trace!("Clock");
 
         // mseq logic
         // post tick
-        (&mut mseq_ctx, &mut midi_controller)
-            .lock(|mseq_ctx, midi_controller| mseq_ctx.process_post_tick(midi_controller));
+        let (playing, step) =
+            (&mut mseq_ctx, &mut midi_controller).lock(|mseq_ctx, midi_controller| {
+                let step = mseq_ctx.get_step();
+                mseq_ctx.process_post_tick(midi_controller);
+                // The step only moves while the sequencer plays
+                (mseq_ctx.get_step() != step, mseq_ctx.get_step())
+            });
+        IS_PLAYING.store(playing, Ordering::Relaxed);
+        status_leds.lock(|status_leds| status_leds.show_transport(playing));
+        if playing && step % BEAT_STEPS == 0 {
+            ACTIVITY.beat(step % BAR_STEPS == 0);
+        }
 
-        let mut current_step = 0;
         // pre tick
         (&mut mseq_ctx, &mut midi_controller, &mut conductor).lock(
             |mseq_ctx, midi_controller, conductor| {
                 mseq_ctx.process_pre_tick(conductor, midi_controller);
-                current_step = mseq_ctx.get_step();
             },
         );
-
-        // Screen is refreshed on each beat
-        if current_step % 24 == 1 {
-            // Update display text
-            (&mut conductor, &mut mseq_ctx, &mut display_text).lock(
-                |conductor, ctx, display_text| {
-                    *display_text = conductor.display_text(ctx);
-                },
-            );
-            match update_display::spawn() {
-                Ok(_) => (),
-                Err(_) => warn!("Display update skipped"),
-            }
-        }
     }
 
-    #[task(priority = 3, shared = [conductor, midi_controller, mseq_ctx, display_text])]
+    #[task(priority = 3, shared = [conductor, midi_controller, mseq_ctx, status_leds])]
     async fn slave_clock(mut cx: slave_clock::Context) {
         clock(
             &mut cx.shared.mseq_ctx,
             &mut cx.shared.midi_controller,
             &mut cx.shared.conductor,
-            &mut cx.shared.display_text,
+            &mut cx.shared.status_leds,
         );
     }
 
-    #[task(priority = 3, shared = [mseq_ctx])]
-    async fn slave_start(mut cx: slave_start::Context) {
+    // Transport requests from the midi input in slave mode and from the front panel in master
+    // mode. In master mode the matching midi message is sent at the next clock tick. The LEDs
+    // follow right away, a stopped external clock would not refresh them.
+    #[task(priority = 3, shared = [mseq_ctx, status_leds])]
+    async fn transport_start(mut cx: transport_start::Context) {
         cx.shared.mseq_ctx.lock(|ctx| ctx.start());
+        cx.shared
+            .status_leds
+            .lock(|status_leds| status_leds.show_transport(true));
     }
 
-    #[task(priority = 3, shared = [mseq_ctx])]
-    async fn slave_stop(mut cx: slave_stop::Context) {
+    #[task(priority = 3, shared = [mseq_ctx, status_leds])]
+    async fn transport_stop(mut cx: transport_stop::Context) {
         cx.shared.mseq_ctx.lock(|ctx| ctx.pause());
+        // The settings can be saved even if the external clock stops with the sequencer
+        IS_PLAYING.store(false, Ordering::Relaxed);
+        cx.shared
+            .status_leds
+            .lock(|status_leds| status_leds.show_transport(false));
     }
 
-    #[task(priority = 3, shared = [mseq_ctx])]
-    async fn slave_continue(mut cx: slave_continue::Context) {
+    #[task(priority = 3, shared = [mseq_ctx, status_leds])]
+    async fn transport_continue(mut cx: transport_continue::Context) {
         cx.shared.mseq_ctx.lock(|ctx| ctx.resume());
+        cx.shared
+            .status_leds
+            .lock(|status_leds| status_leds.show_transport(true));
     }
 
     // Midi interrupt
-    #[task(binds = USART1, priority = 4, local=[rx, midi_input_handler, input_signal_writer, is_master], shared = [input_queue])]
+    #[task(binds = USART1, priority = 4, local=[rx, midi_input_handler, input_signal_writer, sysex_signal_writer], shared = [input_queue, sysex_queue])]
     fn midi_int(mut cx: midi_int::Context) {
-        let serial = cx.local.rx;
-        match serial.read() {
-            Ok(b) => {
-                debug!("{b} received");
-                cx.local
-                    .midi_input_handler
-                    .process_byte(b)
-                    .map(|midi_message| {
-                        match midi_message {
-                            MidiMessage::Clock => {
-                                if !*cx.local.is_master {
-                                    if let Err(()) = slave_clock::spawn() {
-                                        error!("Clock cycle skipped")
-                                    }
-                                } else {
-                                    warn!("Received clock signal but mode is set to master")
-                                }
-                            }
-                            MidiMessage::Start => {
-                                if !*cx.local.is_master {
-                                    if let Err(()) = slave_start::spawn() {
-                                        error!("Failed to start sequencer")
-                                    }
-                                } else {
-                                    warn!("Received start signal but mode is set to master")
-                                }
-                            }
-                            MidiMessage::Stop => {
-                                if !*cx.local.is_master {
-                                    if let Err(()) = slave_stop::spawn() {
-                                        error!("Failed to stop sequencer")
-                                    }
-                                } else {
-                                    warn!("Received stop signal but mode is set to master")
+        match cx.local.rx.read() {
+            Ok(b) => process_midi_byte(
+                b,
+                Port::Din,
+                cx.local.midi_input_handler,
+                &mut cx.shared.input_queue,
+                &mut cx.shared.sysex_queue,
+                cx.local.input_signal_writer,
+                cx.local.sysex_signal_writer,
+            ),
+            Err(_) => error!("Serial error"),
+        }
+    }
+
+    // USB interrupt, the host is another midi input with its own parser, the console input is
+    // handled by `console_int`
+    #[cfg(feature = "usb")]
+    // RTIC does not copy the cfg of a task to its function
+    #[cfg_attr(not(feature = "usb"), cfg(any()))]
+    #[task(binds = OTG_FS, priority = 4, local=[usb_input_handler, usb_input_signal_writer, usb_sysex_signal_writer, console_sender], shared = [input_queue, sysex_queue])]
+    fn usb_int(mut cx: usb_int::Context) {
+        let received = usb::poll();
+        for b in received.midi {
+            process_midi_byte(
+                b,
+                Port::Usb,
+                cx.local.usb_input_handler,
+                &mut cx.shared.input_queue,
+                &mut cx.shared.sysex_queue,
+                cx.local.usb_input_signal_writer,
+                cx.local.usb_sysex_signal_writer,
+            );
+        }
+        if !received.console.is_empty() {
+            for b in received.console {
+                if cx.local.console_sender.try_send(b).is_err() {
+                    warn!("Console input dropped");
+                    break;
+                }
+            }
+            rtic::pend(Interrupt::SPI3);
+        }
+    }
+
+    // Parses a byte of a midi input and dispatches the completed message
+    fn process_midi_byte(
+        b: u8,
+        port: Port,
+        midi_input_handler: &mut MidiInputHandler,
+        input_queue: &mut impl Mutex<T = InputQueue>,
+        sysex_queue: &mut impl Mutex<T = heapless::Deque<(Port, SysEx), SYSEX_QUEUE_SIZE>>,
+        input_signal_writer: &mut SignalWriter<'static, ()>,
+        sysex_signal_writer: &mut SignalWriter<'static, ()>,
+    ) {
+        let is_master = IS_MASTER.load(Ordering::Relaxed);
+        deferred!(Level::Trace, "{} received", b);
+        if let Some(midi_message) = midi_input_handler.process_byte(b) {
+            // The clock would keep the LED lit
+            if midi_message != MidiMessage::Clock {
+                ACTIVITY.midi_in();
+            }
+            match midi_message {
+                MidiMessage::Clock => {
+                    if !is_master {
+                        if let Err(()) = slave_clock::spawn() {
+                            error!("Clock cycle skipped")
+                        }
+                    } else {
+                        warn!("Received clock signal but mode is set to master")
+                    }
+                }
+                MidiMessage::Start => {
+                    if !is_master {
+                        if let Err(()) = transport_start::spawn() {
+                            error!("Failed to start sequencer")
+                        }
+                    } else {
+                        warn!("Received start signal but mode is set to master")
+                    }
+                }
+                MidiMessage::Stop => {
+                    if !is_master {
+                        if let Err(()) = transport_stop::spawn() {
+                            error!("Failed to stop sequencer")
+                        }
+                    } else {
+                        warn!("Received stop signal but mode is set to master")
+                    }
+                }
+                MidiMessage::Continue => {
+                    if !is_master {
+                        if let Err(()) = transport_continue::spawn() {
+                            error!("Failed to continue sequencer")
+                        }
+                    } else {
+                        warn!("Received continue signal but mode is set to master")
+                    }
+                }
+                _ => {
+                    input_queue.lock(|input_queue| input_queue.push_back(midi_message));
+                    input_signal_writer.write(());
+                }
+            };
+        }
+        if let Some(sysex) = midi_input_handler.take_sysex() {
+            ACTIVITY.midi_in();
+            let queued =
+                sysex_queue.lock(|sysex_queue| sysex_queue.push_back((port, sysex)).is_ok());
+            if queued {
+                sysex_signal_writer.write(());
+            } else {
+                warn!("SysEx message dropped");
+            }
+        }
+    }
+
+    // Front panel button interrupt
+    #[task(binds = EXTI15_10, priority = 2, local = [panel_int_signal_writer], shared = [panel, panel_queue])]
+    fn panel_int(mut cx: panel_int::Context) {
+        cx.shared.panel.lock(|panel| panel.clear_interrupts());
+        read_panel(
+            &mut cx.shared.panel,
+            &mut cx.shared.panel_queue,
+            cx.local.panel_int_signal_writer,
+        );
+    }
+
+    // Encoder changes and button debouncing do not raise interrupts
+    #[task(priority = 2, local = [panel_poll_signal_writer], shared = [panel, panel_queue])]
+    async fn poll_panel(mut cx: poll_panel::Context) {
+        loop {
+            Mono::delay(PANEL_POLL_PERIOD_MS.millis()).await;
+            read_panel(
+                &mut cx.shared.panel,
+                &mut cx.shared.panel_queue,
+                cx.local.panel_poll_signal_writer,
+            );
+        }
+    }
+
+    fn read_panel(
+        panel: &mut panel_that_needs_to_be_locked,
+        panel_queue: &mut panel_queue_that_needs_to_be_locked,
+        panel_signal_writer: &mut SignalWriter<'static, ()>,
+    ) {
+        let now_ms = Mono::now().duration_since_epoch().to_millis();
+        let mut received = false;
+        (panel, panel_queue).lock(|panel, panel_queue| {
+            panel.poll(now_ms, |event| {
+                match panel_queue.push_back((now_ms, event)) {
+                    Ok(()) => received = true,
+                    Err(_) => warn!("Panel event dropped"),
+                }
+            })
+        });
+        if received {
+            panel_signal_writer.write(());
+        }
+    }
+
+    fn transport_button(id: u8) {
+        // In slave mode the transport follows the midi input
+        if !IS_MASTER.load(Ordering::Relaxed) {
+            warn!("Transport buttons are disabled in slave mode");
+            return;
+        }
+        let spawned = match id {
+            driver::PLAY_BUTTON => transport_start::spawn(),
+            driver::STOP_BUTTON => transport_stop::spawn(),
+            _ => transport_continue::spawn(),
+        };
+        if let Err(()) = spawned {
+            error!("Transport request dropped")
+        }
+    }
+
+    // The menu runs at the lowest priority so that it never delays the clock or the midi inputs
+    #[task(priority = 1, local = [panel_refresh_signal_writer], shared = [mseq_ctx, conductor, panel_queue, diagnostics, menu])]
+    async fn panel_input(
+        mut cx: panel_input::Context,
+        mut panel_signal_reader: SignalReader<'static, ()>,
+    ) {
+        let mut pressed_at = None;
+        loop {
+            panel_signal_reader.wait().await;
+
+            let mut events = heapless::Deque::<(u32, PanelEvent), PANEL_QUEUE_SIZE>::new();
+            cx.shared
+                .panel_queue
+                .lock(|panel_queue| events = core::mem::take(panel_queue));
+            let event_count = events.len() as u32;
+
+            for (time_ms, event) in events {
+                let menu_open = cx.shared.menu.lock(|menu| menu.is_open());
+                let action = match event {
+                    PanelEvent::ButtonPress {
+                        id:
+                            id @ (driver::PLAY_BUTTON | driver::STOP_BUTTON | driver::CONTINUE_BUTTON),
+                    } => PanelAction::Transport(id),
+                    PanelEvent::ButtonPress {
+                        id: driver::ENCODER_BUTTON,
+                    } => {
+                        // Short and long presses are told apart on release
+                        pressed_at = Some(time_ms);
+                        continue;
+                    }
+                    PanelEvent::ButtonRelease {
+                        id: driver::ENCODER_BUTTON,
+                    } => {
+                        let held_ms = pressed_at.take().map_or(0, |at| time_ms.wrapping_sub(at));
+                        if held_ms >= LONG_PRESS_MS {
+                            PanelAction::ToggleMenu
+                        } else if menu_open {
+                            PanelAction::Menu(MenuInput::Press)
+                        } else {
+                            PanelAction::Forward(PanelEvent::ButtonPress {
+                                id: driver::ENCODER_BUTTON,
+                            })
+                        }
+                    }
+                    PanelEvent::EncoderTurn {
+                        id: driver::MAIN_ENCODER,
+                        delta,
+                    } if menu_open => PanelAction::Menu(MenuInput::Turn(delta)),
+                    // The other controls are disabled while the menu is open
+                    _ if menu_open => continue,
+                    event => PanelAction::Forward(event),
+                };
+
+                match action {
+                    PanelAction::Transport(id) => transport_button(id),
+                    PanelAction::Forward(event) => {
+                        (&mut cx.shared.conductor, &mut cx.shared.mseq_ctx)
+                            .lock(|conductor, mseq_ctx| conductor.handle_panel(event, mseq_ctx))
+                    }
+                    PanelAction::ToggleMenu => cx
+                        .shared
+                        .menu
+                        .lock(|menu| if menu_open { menu.close() } else { menu.open() }),
+                    PanelAction::Menu(input) => {
+                        let changed = (&mut cx.shared.menu, &mut cx.shared.conductor).lock(
+                            |menu, conductor| {
+                                let track_count = conductor.track_count();
+                                menu.handle(input, conductor.settings_mut(), track_count)
+                            },
+                        );
+                        (&mut cx.shared.conductor, &mut cx.shared.mseq_ctx).lock(
+                            |conductor, mseq_ctx| match changed {
+                                Some(MenuAction::Setting(setting)) => {
+                                    conductor.apply_setting(setting, mseq_ctx);
+                                    let is_master =
+                                        conductor.settings().clock_mode == ClockMode::Master;
+                                    IS_MASTER.store(is_master, Ordering::Relaxed);
                                 }
-                            }
-                            MidiMessage::Continue => {
-                                if !*cx.local.is_master {
-                                    if let Err(()) = slave_continue::spawn() {
-                                        error!("Failed to continue sequencer")
-                                    }
-                                } else {
-                                    warn!("Received continue signal but mode is set to master")
+                                Some(MenuAction::Learn(param)) => {
+                                    conductor.learn_mut().start(param)
                                 }
-                            }
-                            _ => {
-                                cx.shared
-                                    .input_queue
-                                    .lock(|input_queue| input_queue.push_back(midi_message));
-                                cx.local.input_signal_writer.write(());
-                            }
-                        };
-                    });
+                                Some(MenuAction::ClearLearn) => conductor.learn_mut().clear(),
+                                None => {}
+                            },
+                        );
+                    }
+                }
+            }
+            cx.shared
+                .diagnostics
+                .lock(|diagnostics| diagnostics.inputs += event_count);
+
+            if REFRESH_POLICY.refresh_on_input {
+                cx.local.panel_refresh_signal_writer.write(());
             }
-            Err(_) => error!("Serial error"),
         }
     }
 
-    #[task(priority = 2, shared = [mseq_ctx, conductor, midi_controller, input_queue])]
+    // The NRPN messages of the control channel and the learned controllers are handled before
+    // reaching the conductor
+    #[task(priority = 2, local = [refresh_signal_writer, nrpn_decoder: NrpnDecoder = NrpnDecoder::new()], shared = [mseq_ctx, conductor, midi_controller, input_queue, diagnostics])]
     async fn handle_input(
         mut cx: handle_input::Context,
         mut input_signal_reader: SignalReader<'static, ()>,
@@ -337,20 +799,263 @@ mod app {
 
             let mut inputs = InputQueue::new();
             input_queue.lock(|input_queue| inputs = core::mem::take(input_queue));
+
+            let input_count = inputs.len() as u32;
+            let decoder = &mut *cx.local.nrpn_decoder;
             (&mut *ctx, &mut *conductor, &mut *controller).lock(
                 |mseq_ctx, conductor, controller| {
-                    mseq_ctx.handle_input(conductor, controller, &mut inputs)
+                    inputs.retain(|input| match decoder.decode(input) {
+                        Some(Some(nrpn)) => {
+                            conductor.handle_nrpn(nrpn, mseq_ctx);
+                            false
+                        }
+                        Some(None) => false,
+                        None => !conductor.handle_learned(input, mseq_ctx),
+                    });
+                    mseq_ctx.handle_input(conductor, controller, &mut inputs);
                 },
             );
+            cx.shared
+                .diagnostics
+                .lock(|diagnostics| diagnostics.inputs += input_count);
+
+            // Inputs can change the content of the current page
+            if REFRESH_POLICY.refresh_on_input {
+                cx.local.refresh_signal_writer.write(());
+            }
         }
     }
 
-    #[task(priority = 1, local = [display], shared = [display_text])]
-    async fn update_display(mut cx: update_display::Context) {
-        if let Some(display) = cx.local.display.as_mut() {
+    // Uploads and dumps run at the lowest priority, the replies are written outside of the lock of
+    // the midi controller so that the clock is not held while they are sent
+    #[task(priority = 1, local = [receiver], shared = [sysex_queue, conductor, midi_controller])]
+    async fn handle_sysex(
+        mut cx: handle_sysex::Context,
+        mut sysex_signal_reader: SignalReader<'static, ()>,
+    ) {
+        loop {
+            sysex_signal_reader.wait().await;
+
+            let mut messages = heapless::Deque::<(Port, SysEx), SYSEX_QUEUE_SIZE>::new();
             cx.shared
-                .display_text
-                .lock(|display_text| display.update(display_text))
+                .sysex_queue
+                .lock(|sysex_queue| messages = core::mem::take(sysex_queue));
+
+            for (port, sysex) in messages {
+                if sysex::is_identity_request(&sysex) {
+                    debug!("Identity request");
+                    reply_sysex(&identity_reply(dump::VERSION));
+                    continue;
+                }
+                let message = Message::parse(&sysex);
+                // The bootloader only listens to the DIN input
+                if let Ok(Message::FirmwareBegin { .. }) = message
+                    && port != Port::Din
+                {
+                    warn!("Firmware update refused on {port:?}, send it to the DIN input");
+                    reply_sysex(&Message::Nak(Nak::Invalid).to_sysex());
+                    continue;
+                }
+                // The bootloader receives the firmware, the host sends the request again
+                if let Ok(Message::FirmwareBegin { .. }) = message {
+                    info!("Restarting in the bootloader");
+                    cx.shared
+                        .midi_controller
+                        .lock(|midi_controller| midi_controller.finish());
+                    driver::reboot_to_bootloader();
+                }
+                if let Ok(Message::DumpRequest(kind)) = message {
+                    debug!("Dump request: {kind:?}");
+                    let data = cx
+                        .shared
+                        .conductor
+                        .lock(|conductor| dump::dump(kind, conductor));
+                    let reply = Message::Dump { dump: kind, data };
+                    reply_sysex(&reply.to_sysex());
+                    continue;
+                }
+                let reply = match message.map(|m| cx.local.receiver.handle(m)) {
+                    Err(ParseError::OtherDevice) => continue,
+                    Err(ParseError::Checksum) => Message::Nak(Nak::Checksum),
+                    Err(e) => {
+                        warn!("Invalid SysEx message: {e}");
+                        continue;
+                    }
+                    Ok(Ok(None)) => Message::Ack,
+                    Ok(Ok(Some((slot, track)))) => {
+                        match cx
+                            .shared
+                            .conductor
+                            .lock(|conductor| conductor.load_track(slot as usize, &track))
+                        {
+                            Ok(()) => {
+                                info!("Track uploaded to slot {slot}");
+                                Message::Ack
+                            }
+                            Err(e) => {
+                                warn!("Upload to slot {slot} rejected: {e}");
+                                Message::Nak(Nak::Invalid)
+                            }
+                        }
+                    }
+                    Ok(Err(nak)) => {
+                        warn!("Upload message rejected: {nak}");
+                        Message::Nak(nak)
+                    }
+                };
+                reply_sysex(&reply.to_sysex());
+            }
+        }
+    }
+
+    fn reply_sysex(bytes: &[u8]) {
+        if let Err(e) = send_sysex(bytes) {
+            error!("Failed to send SysEx reply: {e}");
+        }
+    }
+
+    // Parameters changed from any control are sent on the midi output, see `user::nrpn`
+    #[task(priority = 1, shared = [conductor, midi_controller])]
+    async fn echo_nrpn(mut cx: echo_nrpn::Context) {
+        loop {
+            Mono::delay(ECHO_PERIOD_MS.millis()).await;
+            let messages = cx.shared.conductor.lock(|conductor| conductor.nrpn_echo());
+            for message in messages {
+                if let MidiMessage::CC {
+                    channel,
+                    controller,
+                    value,
+                } = message
+                    && let Err(e) = cx
+                        .shared
+                        .midi_controller
+                        .lock(|_| send_cc(channel, controller, value))
+                {
+                    error!("Failed to echo NRPN: {e}");
+                }
+            }
+        }
+    }
+
+    // Commands of the USB console, run at the lowest priority like the menu.
+    // RTIC cannot leave out a software task with a cfg, the console runs on an interrupt of an
+    // unused peripheral instead, pended by `usb_int`.
+    #[cfg(feature = "usb")]
+    #[cfg_attr(not(feature = "usb"), cfg(any()))]
+    #[task(binds = SPI3, priority = 1, local = [console_receiver, line_editor], shared = [conductor, mseq_ctx, diagnostics])]
+    fn console_int(mut cx: console_int::Context) {
+        while let Ok(b) = cx.local.console_receiver.try_recv() {
+            let Some(line) = cx.local.line_editor.input(b) else {
+                continue;
+            };
+            let output = match Command::parse(&line) {
+                Ok(Some(command)) => (
+                    &mut cx.shared.conductor,
+                    &mut cx.shared.mseq_ctx,
+                    &mut cx.shared.diagnostics,
+                )
+                    .lock(|conductor, mseq_ctx, diagnostics| {
+                        command.run(conductor, mseq_ctx, diagnostics)
+                    }),
+                Ok(None) => String::new(),
+                Err(e) => format!("{e}\r\n"),
+            };
+            usb::write_console(output.as_bytes());
+            console::prompt();
+        }
+    }
+
+    #[task(priority = 1, local = [activity_leds])]
+    async fn update_leds(cx: update_leds::Context) {
+        loop {
+            Mono::delay(LED_PERIOD_MS.millis()).await;
+            let now_ms = Mono::now().duration_since_epoch().to_millis();
+            cx.local.activity_leds.update(now_ms);
+        }
+    }
+
+    #[task(priority = 1)]
+    async fn poll_log_commands(_: poll_log_commands::Context) {
+        loop {
+            Mono::delay(LOG_COMMAND_PERIOD_MS.millis()).await;
+            rtt_logger::poll();
+        }
+    }
+
+    #[task(priority = 1, local = [store], shared = [conductor])]
+    async fn save_state(mut cx: save_state::Context) {
+        let Some(store) = cx.local.store else {
+            return;
+        };
+        loop {
+            Mono::delay(SAVE_PERIOD_MS.millis()).await;
+            // Writing or erasing the flash stalls the CPU, which would delay the clock
+            if IS_PLAYING.load(Ordering::Relaxed) {
+                continue;
+            }
+            let state = cx
+                .shared
+                .conductor
+                .lock(|conductor| conductor.saved_state());
+            // Only the values that changed are written
+            for (key, value) in state {
+                if let Err(e) = store.write(key, &value) {
+                    error!("Failed to save key {key}: {e}");
+                }
+            }
+        }
+    }
+
+    #[task(priority = 1, local = [display, display_text, crash_text], shared = [mseq_ctx, conductor, diagnostics, menu])]
+    async fn update_display(
+        mut cx: update_display::Context,
+        mut refresh_signal_reader: SignalReader<'static, ()>,
+    ) {
+        if let Some(text) = cx.local.crash_text.take() {
+            if let Some(display) = cx.local.display.as_mut() {
+                display.update(&text);
+            }
+            Mono::delay(CRASH_REPORT_DURATION_S.secs()).await;
+        }
+
+        let frame_period = REFRESH_POLICY.frame_period_ms.millis();
+        let mut last_frame = Mono::now();
+        loop {
+            // Wait for the next frame unless an input asks for an immediate refresh
+            let _ = Mono::timeout_after(frame_period, refresh_signal_reader.wait()).await;
+            let now = Mono::now();
+            let elapsed_ms = (now - last_frame).to_millis();
+            last_frame = now;
+
+            let (heap_used, heap_free) = heap::heap_stats();
+            let text = (
+                &mut cx.shared.conductor,
+                &mut cx.shared.mseq_ctx,
+                &mut cx.shared.diagnostics,
+                &mut cx.shared.menu,
+            )
+                .lock(|conductor, ctx, diagnostics, menu| {
+                    diagnostics.heap_used = heap_used;
+                    diagnostics.heap_free = heap_free;
+                    if menu.is_open() {
+                        menu.display_text(
+                            conductor.settings(),
+                            conductor.learn(),
+                            &conductor.track_names(),
+                        )
+                    } else {
+                        conductor.display_text(ctx, diagnostics)
+                    }
+                });
+
+            if let Some(display) = cx.local.display.as_mut() {
+                // Only send the whole content when it changed
+                if text != *cx.local.display_text {
+                    display.update(&text);
+                }
+                display.tick(elapsed_ms);
+            }
+            *cx.local.display_text = text;
         }
     }
 }
@@ -1,22 +1,101 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::{self, Mutex};
 use driver::{DriverError, write};
-use log::debug;
-use mseq_core::MidiNote;
+use heapless::Vec;
+use log::{Level, debug};
 use stm32f4xx_hal::{pac::USART1, serial::Tx};
 use thiserror::Error;
 
+use crate::deferred;
+use crate::status_leds::ACTIVITY;
+
 #[derive(Error, Debug)]
 pub enum MidiError {
     #[error("Error when calling Diver.\n\tDriver: {0}")]
     Util(#[from] DriverError),
+    #[error("Midi output already in use.")]
+    Busy,
+}
+
+// Serial output, taken by `MidiOut` and `send_cc` under the lock of the midi controller, and by
+// `send_sysex` outside of it. The messages sent while a SysEx message is written are kept in
+// `PENDING` and written after it.
+static MIDI_TX: Mutex<RefCell<Option<Tx<USART1>>>> = Mutex::new(RefCell::new(None));
+
+// Bytes of the messages sent while a SysEx message is written
+const PENDING_LEN: usize = 128;
+static PENDING: Mutex<RefCell<Vec<u8, PENDING_LEN>>> = Mutex::new(RefCell::new(Vec::new()));
+
+fn write_tx(bytes: &[u8]) -> Result<(), MidiError> {
+    // The host receives the same messages as the DIN output
+    #[cfg(feature = "usb")]
+    crate::usb::write_midi(bytes);
+    let tx = interrupt::free(|cs| -> Result<_, MidiError> {
+        let tx = MIDI_TX.borrow(cs).take();
+        if tx.is_none() {
+            PENDING
+                .borrow(cs)
+                .borrow_mut()
+                .extend_from_slice(bytes)
+                .map_err(|_| MidiError::Busy)?;
+        }
+        Ok(tx)
+    })?;
+    let Some(mut tx) = tx else {
+        return Ok(());
+    };
+    let result = write(&mut tx, bytes);
+    interrupt::free(|cs| MIDI_TX.borrow(cs).replace(Some(tx)));
+    Ok(result?)
+}
+
+/// Sends a SysEx message. It is written outside of the lock of the midi controller so that the
+/// clock is not held while it is sent, the messages sent meanwhile are written after it.
+pub fn send_sysex(bytes: &[u8]) -> Result<(), MidiError> {
+    ACTIVITY.midi_out();
+    #[cfg(feature = "usb")]
+    crate::usb::write_midi(bytes);
+    let mut tx = interrupt::free(|cs| MIDI_TX.borrow(cs).take());
+    let Some(out) = tx.as_mut() else {
+        return Err(MidiError::Busy);
+    };
+    let mut result = write(out, bytes);
+    // The output is given back once no message is pending
+    loop {
+        let pending = interrupt::free(|cs| {
+            let pending = core::mem::take(&mut *PENDING.borrow(cs).borrow_mut());
+            if pending.is_empty() {
+                MIDI_TX.borrow(cs).replace(tx.take());
+            }
+            pending
+        });
+        let Some(out) = tx.as_mut() else {
+            break;
+        };
+        result = result.and(write(out, &pending));
+    }
+    Ok(result?)
 }
 
-pub struct MidiOut {
-    tx: Tx<USART1>,
+/// Sends a Control Change, must be called under the lock of the midi controller.
+pub fn send_cc(channel: u8, controller: u8, value: u8) -> Result<(), MidiError> {
+    ACTIVITY.midi_out();
+    write_tx(&[CC | (channel - 1), controller, value])
 }
 
+pub struct MidiOut(());
+
 impl MidiOut {
     pub fn new(tx: Tx<USART1>) -> Self {
-        Self { tx }
+        interrupt::free(|cs| MIDI_TX.borrow(cs).replace(Some(tx)));
+        Self(())
+    }
+
+    // Sends a message that is shown by the midi output LED
+    fn send(&mut self, bytes: &[u8]) -> Result<(), MidiError> {
+        ACTIVITY.midi_out();
+        write_tx(bytes)
     }
 }
 
@@ -28,54 +107,62 @@ pub const NOTE_ON: u8 = 0x90;
 pub const NOTE_OFF: u8 = 0x80;
 pub const CC: u8 = 0xB0;
 pub const PC: u8 = 0xC0;
+pub const ALL_NOTES_OFF: u8 = 123;
 
 impl mseq_core::MidiOut for MidiOut {
     type Error = MidiError;
     fn send_start(&mut self) -> Result<(), MidiError> {
         debug!("Send Start");
-        Ok(write(&mut self.tx, &[START])?)
+        self.send(&[START])
     }
     fn send_continue(&mut self) -> Result<(), MidiError> {
         debug!("Send Continue");
-        Ok(write(&mut self.tx, &[CONTINUE])?)
+        self.send(&[CONTINUE])
     }
     fn send_stop(&mut self) -> Result<(), MidiError> {
         debug!("Send Stop");
-        Ok(write(&mut self.tx, &[STOP])?)
+        self.send(&[STOP])
     }
     fn send_clock(&mut self) -> Result<(), MidiError> {
         debug!("Send Clock");
-        Ok(write(&mut self.tx, &[CLOCK])?)
+        write_tx(&[CLOCK])
     }
     fn send_note_on(&mut self, channel_id: u8, note: u8, velocity: u8) -> Result<(), MidiError> {
-        debug!(
-            "Send Note On: Channel: {channel_id}, Note: {:?}",
-            MidiNote::from_midi_value(note, velocity)
+        deferred!(
+            Level::Debug,
+            "Send Note On: Channel: {}, Note: {}, Velocity: {}",
+            channel_id,
+            note,
+            velocity
         );
-        Ok(write(
-            &mut self.tx,
-            &[NOTE_ON | (channel_id - 1), note, velocity],
-        )?)
+        self.send(&[NOTE_ON | (channel_id - 1), note, velocity])
     }
     fn send_note_off(&mut self, channel_id: u8, note: u8) -> Result<(), MidiError> {
-        debug!(
-            "Send Note Off: Channel: {channel_id}, Note: {:?}",
-            MidiNote::from_midi_value(note, 0)
+        deferred!(
+            Level::Debug,
+            "Send Note Off: Channel: {}, Note: {}",
+            channel_id,
+            note
         );
-        Ok(write(
-            &mut self.tx,
-            &[NOTE_OFF | (channel_id - 1), note, 0],
-        )?)
+        self.send(&[NOTE_OFF | (channel_id - 1), note, 0])
     }
     fn send_cc(&mut self, channel_id: u8, parameter: u8, value: u8) -> Result<(), MidiError> {
-        debug!("Send CC: Channel: {channel_id}, paramerte: {parameter}, value: {value}");
-        Ok(write(
-            &mut self.tx,
-            &[CC | (channel_id - 1), parameter, value],
-        )?)
+        deferred!(
+            Level::Debug,
+            "Send CC: Channel: {}, parameter: {}, value: {}",
+            channel_id,
+            parameter,
+            value
+        );
+        self.send(&[CC | (channel_id - 1), parameter, value])
     }
     fn send_pc(&mut self, channel_id: u8, value: u8) -> Result<(), MidiError> {
-        debug!("Send PC: Channel: {channel_id}, value: {value}");
-        Ok(write(&mut self.tx, &[PC | (channel_id - 1), value])?)
+        deferred!(
+            Level::Debug,
+            "Send PC: Channel: {}, value: {}",
+            channel_id,
+            value
+        );
+        self.send(&[PC | (channel_id - 1), value])
     }
 }
@@ -1,10 +1,28 @@
+use log::Level;
 use mseq_core::{MidiMessage, MidiNote};
+use sysex::{SYSEX_END, SYSEX_START};
 
+use crate::deferred;
 use crate::midi_connection::{CC, CLOCK, CONTINUE, NOTE_OFF, NOTE_ON, PC, START, STOP};
 
+/// Longest SysEx message received, from its start to its end byte.
+pub const SYSEX_LEN: usize = 128;
+pub type SysEx = heapless::Vec<u8, SYSEX_LEN>;
+
+/// Midi input a message was received from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Port {
+    Din,
+    #[cfg(feature = "usb")]
+    Usb,
+}
+
 pub struct MidiInputHandler {
     size: u8,
     data: [u8; 3],
+    // SysEx message being received, dropped if it is too long
+    sysex: Option<SysEx>,
+    received_sysex: Option<SysEx>,
 }
 
 impl MidiInputHandler {
@@ -12,8 +30,48 @@ impl MidiInputHandler {
         Self {
             size: 0,
             data: [0; 3],
+            sysex: None,
+            received_sysex: None,
         }
     }
+
+    /// Returns the last SysEx message completed by [`MidiInputHandler::process_byte`].
+    pub fn take_sysex(&mut self) -> Option<SysEx> {
+        self.received_sysex.take()
+    }
+
+    // Returns `true` if the byte belongs to a SysEx message
+    fn process_sysex(&mut self, byte: u8) -> bool {
+        match byte {
+            SYSEX_START => {
+                self.clear();
+                self.sysex = Some(SysEx::new());
+            }
+            // The other real-time messages can be interleaved anywhere, they are ignored
+            0xf8.. => return true,
+            // Any other status byte ends the message
+            b if b & 0x80 != 0 && b != SYSEX_END => {
+                self.sysex = None;
+                return false;
+            }
+            _ if self.sysex.is_none() => return byte == SYSEX_END,
+            _ => {}
+        }
+        if let Some(sysex) = self.sysex.as_mut()
+            && sysex.push(byte).is_err()
+        {
+            deferred!(
+                Level::Warn,
+                "SysEx message longer than {} bytes dropped",
+                SYSEX_LEN
+            );
+            self.sysex = None;
+        }
+        if byte == SYSEX_END {
+            self.received_sysex = self.sysex.take();
+        }
+        true
+    }
     fn push(&mut self, data: u8) {
         self.data[self.size as usize] = data;
         self.size += 1;
@@ -30,6 +88,9 @@ impl MidiInputHandler {
             CONTINUE => return Some(MidiMessage::Continue),
             _ => {}
         }
+        if self.process_sysex(byte) {
+            return None;
+        }
         // Append the byte to the buffer
         self.push(byte);
 
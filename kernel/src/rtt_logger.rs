@@ -1,16 +1,409 @@
+//! Logger printing on the RTT terminal.
+//!
+//! The levels are changed at runtime by writing a line on the RTT down channel (e.g. from the
+//! terminal of `cargo embed`), with the syntax of `RUST_LOG`:
+//! * `debug` sets the global level
+//! * `user::conductor=trace` sets the level of a module and its submodules
+//! * `user::conductor=` goes back to the global level for the module
+//!
+//! Several settings can be separated by commas. The levels above the `max_level` features of the
+//! `log` crate are removed at compile time.
+//!
+//! Logging does not print: the records are pushed to lock-free queues and printed by [`drain`]
+//! from the idle loop, the records logged while the queues are full are counted and reported.
+//! The interrupts and the clock log with [`deferred!`](crate::deferred), which queues the format
+//! string and the integer arguments as they are and leaves the formatting to [`drain`]. The
+//! records of the `log` macros are formatted when they are logged, unless their message has no
+//! arguments.
+
+use core::cell::{RefCell, UnsafeCell};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::interrupt::{self, Mutex};
+use heapless::mpmc::MpMcQueue;
 use log::{Level, LevelFilter, Log, Metadata, Record};
-use rtt_target::{rprintln, rtt_init_print};
+use rtt_target::{ChannelMode, DownChannel, rprintln, rtt_init, set_print_channel};
+use thiserror::Error;
+
+const UP_BUFFER_LEN: usize = 1024;
+const DOWN_BUFFER_LEN: usize = 64;
+const LINE_LEN: usize = 64;
+// Modules with their own level
+const MAX_MODULES: usize = 8;
+const MODULE_LEN: usize = 32;
+// Records waiting to be printed, the lengths of the queues must be powers of 2
+const QUEUE_LEN: usize = 32;
+const TEXT_QUEUE_LEN: usize = 8;
+// Integer arguments of a deferred record
+pub const MAX_ARGS: usize = 4;
+// A longer message is truncated
+const MESSAGE_LEN: usize = 96;
 
-pub struct RttLogger {
-    pub level: LevelFilter,
+#[derive(Error, Debug)]
+pub enum FilterError {
+    #[error("Unknown level: {0}")]
+    Level(heapless::String<LINE_LEN>),
+    #[error("Module name too long: {0}")]
+    Module(heapless::String<LINE_LEN>),
+    #[error("Too many module levels, {MAX_MODULES} at most")]
+    Full,
+}
+
+/// Global level and levels of the modules.
+struct Filters {
+    global: LevelFilter,
+    modules: heapless::Vec<(heapless::String<MODULE_LEN>, LevelFilter), MAX_MODULES>,
+}
+
+impl Filters {
+    // Level of the longest module matching `target`
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.global, |(_, level)| *level)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.global, Ord::max)
+    }
+
+    // Applies the comma separated settings of `spec`, the valid settings are kept on error
+    fn apply(&mut self, spec: &str) -> Result<(), FilterError> {
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let Some((module, level)) = setting.split_once('=') else {
+                self.global = parse_level(setting)?;
+                continue;
+            };
+            let module = module.trim();
+            let level = level.trim();
+            self.modules.retain(|(m, _)| m != module);
+            if level.is_empty() {
+                continue;
+            }
+            let level = parse_level(level)?;
+            let module = module
+                .try_into()
+                .map_err(|_| FilterError::Module(truncate(module)))?;
+            self.modules
+                .push((module, level))
+                .map_err(|_| FilterError::Full)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::fmt::Display for Filters {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.global)?;
+        for (module, level) in &self.modules {
+            write!(f, ",{module}={level}")?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, FilterError> {
+    level
+        .parse()
+        .map_err(|_| FilterError::Level(truncate(level)))
+}
+
+fn truncate(s: &str) -> heapless::String<LINE_LEN> {
+    let mut truncated = heapless::String::new();
+    for c in s.chars() {
+        if truncated.push(c).is_err() {
+            break;
+        }
+    }
+    truncated
+}
+
+/// Logs a message without formatting it: `format` is a literal with a `{}` placeholder for each
+/// argument, the arguments are converted to `u32` and the message is formatted by [`drain`].
+/// ```ignore
+/// deferred!(Level::Trace, "{} received", b);
+/// ```
+#[macro_export]
+macro_rules! deferred {
+    ($level:expr, $format:literal $(, $arg:expr)* $(,)?) => {
+        if $level <= log::STATIC_MAX_LEVEL && $level <= log::max_level() {
+            $crate::rtt_logger::defer($level, module_path!(), $format, &[$($arg as u32),*]);
+        }
+    };
+}
+
+/// Record waiting in the queue.
+struct Entry {
+    level: Level,
+    target: &'static str,
+    format: &'static str,
+    // The placeholders are only filled if there are arguments
+    args: heapless::Vec<u32, MAX_ARGS>,
+    // Order of the record among the records of both queues
+    sequence: u32,
+}
+
+/// Record of the `log` macros formatted when it was logged, as its arguments borrow the caller.
+struct Text {
+    level: Level,
+    target: &'static str,
+    message: heapless::String<MESSAGE_LEN>,
+    sequence: u32,
+}
+
+static QUEUE: MpMcQueue<Entry, QUEUE_LEN> = MpMcQueue::new();
+static TEXT_QUEUE: MpMcQueue<Text, TEXT_QUEUE_LEN> = MpMcQueue::new();
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+// Records logged while a queue was full, since the last drain
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Fills the placeholders of a deferred record.
+struct Deferred<'a> {
+    format: &'static str,
+    args: &'a [u32],
+}
+
+impl core::fmt::Display for Deferred<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.args.is_empty() {
+            return f.write_str(self.format);
+        }
+        let mut args = self.args.iter();
+        let mut pieces = self.format.split("{}");
+        f.write_str(pieces.next().unwrap_or_default())?;
+        for piece in pieces {
+            // The placeholders without argument are printed as they are
+            match args.next() {
+                Some(arg) => write!(f, "{arg}")?,
+                None => f.write_str("{}")?,
+            }
+            f.write_str(piece)?;
+        }
+        Ok(())
+    }
+}
+
+/// Filters read without locking: they are only written in a critical section, which the readers
+/// of the interrupts cannot interleave with, and a reader interrupted by a write reads them again.
+struct SharedFilters {
+    filters: UnsafeCell<Filters>,
+    generation: AtomicU32,
+}
+
+// The accesses are synchronized by `generation` on the single core
+unsafe impl Sync for SharedFilters {}
+
+impl SharedFilters {
+    fn update<R>(&self, f: impl FnOnce(&mut Filters) -> R) -> R {
+        interrupt::free(|_| {
+            let result = f(unsafe { &mut *self.filters.get() });
+            self.generation.fetch_add(1, Ordering::Release);
+            result
+        })
+    }
+
+    fn read<R>(&self, mut f: impl FnMut(&Filters) -> R) -> R {
+        loop {
+            let generation = self.generation.load(Ordering::Acquire);
+            let result = f(unsafe { &*self.filters.get() });
+            if self.generation.load(Ordering::Acquire) == generation {
+                return result;
+            }
+        }
+    }
+}
+
+/// Commands received on the down channel.
+struct Commands {
+    channel: DownChannel,
+    line: heapless::String<LINE_LEN>,
+}
+
+struct RttLogger {
+    filters: SharedFilters,
+    commands: Mutex<RefCell<Option<Commands>>>,
+}
+
+static LOGGER: RttLogger = RttLogger {
+    filters: SharedFilters {
+        filters: UnsafeCell::new(Filters {
+            global: LevelFilter::Off,
+            modules: heapless::Vec::new(),
+        }),
+        generation: AtomicU32::new(0),
+    },
+    commands: Mutex::new(RefCell::new(None)),
+};
+
+/// Opens the RTT channels and starts logging from `level`.
+pub fn init(level: LevelFilter) {
+    let channels = rtt_init! {
+        up: {
+            0: {
+                size: UP_BUFFER_LEN,
+                mode: ChannelMode::NoBlockSkip,
+                name: "Terminal"
+            }
+        }
+        down: {
+            0: {
+                size: DOWN_BUFFER_LEN,
+                name: "Terminal"
+            }
+        }
+    };
+    set_print_channel(channels.up.0);
+    LOGGER.filters.update(|filters| filters.global = level);
+    interrupt::free(|cs| {
+        LOGGER.commands.borrow(cs).replace(Some(Commands {
+            channel: channels.down.0,
+            line: heapless::String::new(),
+        }));
+    });
+    log::set_logger(&LOGGER).expect("Failed to set logger");
+    update_max_level();
+}
+
+/// Applies the lines received on the down channel.
+pub fn poll() {
+    interrupt::free(|cs| {
+        let mut commands = LOGGER.commands.borrow(cs).borrow_mut();
+        let Some(Commands { channel, line }) = commands.as_mut() else {
+            return;
+        };
+        let mut buffer = [0; DOWN_BUFFER_LEN];
+        loop {
+            let len = channel.read(&mut buffer);
+            if len == 0 {
+                return;
+            }
+            for &b in &buffer[..len] {
+                match b {
+                    b'\r' | b'\n' if !line.is_empty() => {
+                        set_levels(line);
+                        line.clear();
+                    }
+                    b'\r' | b'\n' => {}
+                    // The end of a line too long is dropped
+                    _ => {
+                        let _ = line.push(b as char);
+                    }
+                }
+            }
+        }
+    });
+}
+
+// Applies a line of the down channel and prints the resulting levels
+fn set_levels(spec: &str) {
+    if let Err(e) = LOGGER.filters.update(|filters| filters.apply(spec)) {
+        rprintln!("Log levels: {}", e);
+    }
+    let mut levels = heapless::String::<{ LINE_LEN * 2 }>::new();
+    // Errors only mean the levels were truncated
+    let _ = LOGGER.filters.read(|filters| {
+        levels.clear();
+        write!(levels, "{}", filters)
+    });
+    rprintln!("Log levels: {}", levels);
+    update_max_level();
+}
+
+/// Lets through the logs enabled on RTT or on the console, the others are skipped by the `log`
+/// macros without formatting.
+pub fn update_max_level() {
+    let level = LOGGER.filters.read(Filters::max);
+    #[cfg(feature = "usb")]
+    let level = level.max(crate::console::log_level());
+    log::set_max_level(level);
+}
+
+/// Queues a record of [`deferred!`](crate::deferred).
+pub fn defer(level: Level, target: &'static str, format: &'static str, args: &[u32]) {
+    if !LOGGER.enabled_for(level, target) {
+        return;
+    }
+    let entry = Entry {
+        level,
+        target,
+        format,
+        // The macro is used with `MAX_ARGS` arguments at most
+        args: heapless::Vec::from_slice(&args[..args.len().min(MAX_ARGS)]).unwrap_or_default(),
+        sequence: SEQUENCE.fetch_add(1, Ordering::Relaxed),
+    };
+    if QUEUE.enqueue(entry).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Prints the queued records in the order they were logged, called by the idle loop and before a
+/// crash is reported.
+pub fn drain() {
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        print(
+            Level::Warn,
+            module_path!(),
+            format_args!("{dropped} log records dropped"),
+        );
+    }
+    let mut entry = QUEUE.dequeue();
+    let mut text = TEXT_QUEUE.dequeue();
+    loop {
+        // The sequence numbers wrap around
+        let entry_first = match (&entry, &text) {
+            (Some(e), Some(t)) => (e.sequence.wrapping_sub(t.sequence) as i32) < 0,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return,
+        };
+        if entry_first && let Some(e) = entry.take() {
+            let message = Deferred {
+                format: e.format,
+                args: &e.args,
+            };
+            print(e.level, e.target, format_args!("{message}"));
+            entry = QUEUE.dequeue();
+        } else if let Some(t) = text.take() {
+            print(t.level, t.target, format_args!("{}", t.message));
+            text = TEXT_QUEUE.dequeue();
+        }
+    }
+}
+
+// Prints a record on RTT and on the console if it is enabled there
+fn print(level: Level, target: &str, message: core::fmt::Arguments) {
+    if level <= LOGGER.rtt_level(target) {
+        let color = RttLogger::color_for(level);
+        let reset = "\x1B[0m";
+        rprintln!("{}[{}]{} {} - {}", color, level, reset, target, message);
+    }
+    // The console has its own level
+    #[cfg(feature = "usb")]
+    crate::console::log(level, target, message);
 }
 
 impl RttLogger {
-    pub fn init(&'static mut self, level: LevelFilter) {
-        rtt_init_print!();
-        self.level = level;
-        log::set_logger(self).expect("Failed to set logger");
-        log::set_max_level(level);
+    fn rtt_level(&self, target: &str) -> LevelFilter {
+        self.filters.read(|filters| filters.level(target))
+    }
+
+    // Records enabled on RTT or on the console
+    fn enabled_for(&self, level: Level, target: &str) -> bool {
+        let enabled = level <= self.rtt_level(target);
+        #[cfg(feature = "usb")]
+        let enabled = enabled || level <= crate::console::log_level();
+        enabled
     }
 
     fn color_for(level: Level) -> &'static str {
@@ -26,23 +419,35 @@ impl RttLogger {
 
 impl Log for RttLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.level
+        self.enabled_for(metadata.level(), metadata.target())
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            let color = Self::color_for(record.level());
-            let reset = "\x1B[0m";
-            rprintln!(
-                "{}[{}]{} {} - {}",
-                color,
-                record.level(),
-                reset,
-                record.target(),
-                record.args()
-            );
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // The target of the macros is the module path unless it is given
+        let target = record.module_path_static().unwrap_or("?");
+        // A message without arguments is queued as it is
+        if let Some(format) = record.args().as_str() {
+            defer(record.level(), target, format, &[]);
+            return;
+        }
+        let mut message = heapless::String::new();
+        // Errors only mean the message was truncated
+        let _ = write!(message, "{}", record.args());
+        let text = Text {
+            level: record.level(),
+            target,
+            message,
+            sequence: SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        };
+        if TEXT_QUEUE.enqueue(text).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        drain();
+    }
 }
@@ -0,0 +1,21 @@
+use stm32f4xx_hal::{
+    gpio::{PB6, PB7},
+    i2c::{I2c, Mode},
+    pac::{I2C1, TIM5},
+    prelude::*,
+    rcc::Clocks,
+};
+
+/// Initializes the screen selected at compile time on I2C1.
+pub fn init(i2c1: I2C1, scl: PB6, sda: PB7, tim5: TIM5, clocks: &Clocks) -> Option<driver::Screen> {
+    let i2c = I2c::new(i2c1, (scl, sda), Mode::standard(50.kHz()), clocks);
+    #[cfg(not(feature = "ssd1306"))]
+    let screen = driver::Lcd::new(i2c, tim5.delay_us(clocks));
+    #[cfg(feature = "ssd1306")]
+    let screen = {
+        // The OLED does not need a delay
+        let _ = tim5;
+        driver::Oled::new(i2c)
+    };
+    screen
+}
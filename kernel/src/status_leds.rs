@@ -0,0 +1,95 @@
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use driver::{Led, PulseLed};
+
+// Length of the LED pulses
+const BEAT_PULSE_MS: u32 = 30;
+const BAR_PULSE_MS: u32 = 150;
+const ACTIVITY_PULSE_MS: u32 = 20;
+
+const NO_BEAT: u8 = 0;
+const BEAT: u8 = 1;
+const BAR: u8 = 2;
+
+/// Events shown by the [`ActivityLeds`].
+/// They are recorded from any priority without locking and consumed by the LED task.
+pub struct Activity {
+    midi_in: AtomicBool,
+    midi_out: AtomicBool,
+    beat: AtomicU8,
+}
+
+pub static ACTIVITY: Activity = Activity {
+    midi_in: AtomicBool::new(false),
+    midi_out: AtomicBool::new(false),
+    beat: AtomicU8::new(NO_BEAT),
+};
+
+impl Activity {
+    pub fn midi_in(&self) {
+        self.midi_in.store(true, Ordering::Relaxed);
+    }
+
+    pub fn midi_out(&self) {
+        self.midi_out.store(true, Ordering::Relaxed);
+    }
+
+    /// Records a quarter note, `downbeat` is `true` on the first beat of a bar.
+    pub fn beat(&self, downbeat: bool) {
+        self.beat
+            .store(if downbeat { BAR } else { BEAT }, Ordering::Relaxed);
+    }
+}
+
+/// LEDs showing the state of the sequencer.
+pub struct StatusLeds {
+    play: Led,
+    stop: Led,
+}
+
+impl StatusLeds {
+    pub fn new(play: Led, stop: Led) -> Self {
+        Self { play, stop }
+    }
+
+    /// Lights the LED of the current transport state.
+    pub fn show_transport(&mut self, playing: bool) {
+        self.play.set(playing);
+        self.stop.set(!playing);
+    }
+}
+
+/// LEDs pulsed on the beat and on midi traffic.
+pub struct ActivityLeds {
+    beat: PulseLed,
+    midi_in: PulseLed,
+    midi_out: PulseLed,
+}
+
+impl ActivityLeds {
+    pub fn new(beat: Led, midi_in: Led, midi_out: Led) -> Self {
+        Self {
+            beat: PulseLed::new(beat),
+            midi_in: PulseLed::new(midi_in),
+            midi_out: PulseLed::new(midi_out),
+        }
+    }
+
+    /// Shows the events recorded in [`ACTIVITY`] since the last call.
+    pub fn update(&mut self, now_ms: u32) {
+        match ACTIVITY.beat.swap(NO_BEAT, Ordering::Relaxed) {
+            BEAT => self.beat.pulse(now_ms, BEAT_PULSE_MS),
+            BAR => self.beat.pulse(now_ms, BAR_PULSE_MS),
+            _ => (),
+        }
+        if ACTIVITY.midi_in.swap(false, Ordering::Relaxed) {
+            self.midi_in.pulse(now_ms, ACTIVITY_PULSE_MS);
+        }
+        if ACTIVITY.midi_out.swap(false, Ordering::Relaxed) {
+            self.midi_out.pulse(now_ms, ACTIVITY_PULSE_MS);
+        }
+        [&mut self.beat, &mut self.midi_in, &mut self.midi_out]
+            .into_iter()
+            .for_each(|led| led.tick(now_ms));
+    }
+}
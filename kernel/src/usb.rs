@@ -0,0 +1,178 @@
+//! USB device on the OTG FS port, enabled by the `usb` feature.
+//!
+//! The device has two functions:
+//! * a MIDI port: the bytes received from the host are parsed like the DIN input, and everything
+//!   sent on the DIN output is copied to the host.
+//! * a serial port (CDC-ACM) running the [console](crate::console).
+
+use core::cell::RefCell;
+
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m::singleton;
+use stm32f4xx_hal::otg_fs::{USB, UsbBus, UsbBusType};
+use sysex::SYSEX_START;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::prelude::*;
+use usbd_midi::class::{MAX_PACKET_SIZE, MIDI_PACKET_SIZE};
+use usbd_midi::{CableNumber, UsbMidiClass, UsbMidiEventPacket, UsbMidiPacketReader};
+use usbd_serial::SerialPort;
+
+// Shared VID/PID of pid.codes for MIDI devices
+const VID_PID: UsbVidPid = UsbVidPid(0x16c0, 0x05e4);
+// Endpoint memory of the OTG FS peripheral, in words
+const EP_MEMORY_LEN: usize = 1024;
+// Midi bytes of a USB packet, a packet holds 3 bytes per 4-byte event
+pub const RECEIVED_LEN: usize = MAX_PACKET_SIZE / MIDI_PACKET_SIZE * 3;
+// Bytes of the console read at each poll
+pub const CONSOLE_RECEIVED_LEN: usize = 64;
+// Buffer of the console output, the logs are written in bursts
+const CONSOLE_BUFFER_LEN: usize = 1024;
+// Midi events waiting for the IN endpoint, the endpoint takes one event at a time
+const MIDI_QUEUE_LEN: usize = 256;
+
+type Console =
+    SerialPort<'static, UsbBusType, [u8; CONSOLE_RECEIVED_LEN], [u8; CONSOLE_BUFFER_LEN]>;
+
+struct Usb {
+    device: UsbDevice<'static, UsbBusType>,
+    midi: UsbMidiClass<'static, UsbBusType>,
+    // Sent when the previous event has been transferred, see `flush_midi`
+    midi_queue: heapless::Deque<UsbMidiEventPacket, MIDI_QUEUE_LEN>,
+    console: Console,
+}
+
+impl Usb {
+    // Sends the queued events until the endpoint is busy, the endpoint interrupt sends the next
+    fn flush_midi(&mut self) {
+        while let Some(packet) = self.midi_queue.front() {
+            match self.midi.send_packet(packet.clone()) {
+                Ok(_) => {
+                    self.midi_queue.pop_front();
+                }
+                Err(UsbError::WouldBlock) => return,
+                // The event cannot be sent
+                Err(_) => {
+                    self.midi_queue.pop_front();
+                }
+            }
+        }
+    }
+}
+
+/// Bytes received from the host at a poll.
+#[derive(Default)]
+pub struct Received {
+    pub midi: heapless::Vec<u8, RECEIVED_LEN>,
+    pub console: heapless::Vec<u8, CONSOLE_RECEIVED_LEN>,
+}
+
+// Polled by the OTG FS interrupt and written by the midi output and the console
+static USB: Mutex<RefCell<Option<Usb>>> = Mutex::new(RefCell::new(None));
+
+/// Starts the device, must be called once.
+/// The 48 MHz clock of the PLL must be enabled.
+pub fn init(usb: USB) {
+    // The memory and the bus are created once, `init` panics if it is called again
+    let bus: &'static UsbBusAllocator<UsbBusType> = singleton!(: UsbBusAllocator<UsbBusType> =
+        UsbBus::new(usb, singleton!(: [u32; EP_MEMORY_LEN] = [0; EP_MEMORY_LEN]).unwrap()))
+    .unwrap();
+    let midi = UsbMidiClass::new(bus, 1, 1).unwrap();
+    let console =
+        SerialPort::new_with_store(bus, [0; CONSOLE_RECEIVED_LEN], [0; CONSOLE_BUFFER_LEN]);
+    let device = UsbDeviceBuilder::new(bus, VID_PID)
+        .composite_with_iads()
+        .strings(&[StringDescriptors::default()
+            .manufacturer("mseq")
+            .product("mseq")
+            .serial_number("1")])
+        .unwrap()
+        .build();
+    interrupt::free(|cs| {
+        USB.borrow(cs).replace(Some(Usb {
+            device,
+            midi,
+            midi_queue: heapless::Deque::new(),
+            console,
+        }))
+    });
+}
+
+/// Handles the events of the device, returns the bytes received from the host.
+pub fn poll() -> Received {
+    let mut received = Received::default();
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    let len = interrupt::free(|cs| {
+        let mut usb = USB.borrow(cs).borrow_mut();
+        let usb = usb.as_mut()?;
+        if !usb.device.poll(&mut [&mut usb.midi, &mut usb.console]) {
+            return None;
+        }
+        // The interrupt is raised when the previous event has been transferred
+        usb.flush_midi();
+        let mut console_buffer = [0; CONSOLE_RECEIVED_LEN];
+        if let Ok(len) = usb.console.read(&mut console_buffer) {
+            let _ = received.console.extend_from_slice(&console_buffer[..len]);
+        }
+        usb.midi.read(&mut buffer).ok()
+    });
+    let Some(len) = len else {
+        return received;
+    };
+    for packet in UsbMidiPacketReader::new(&buffer, len).flatten() {
+        // The packet holds at most 3 bytes
+        let _ = received.midi.extend_from_slice(packet.payload_bytes());
+    }
+    received
+}
+
+/// Sends a midi message to the host, dropped if the host is not connected or if the queue of the
+/// events is full.
+pub fn write_midi(bytes: &[u8]) {
+    // A SysEx message is split into packets of 3 bytes, the other messages fit in one packet
+    let packet_len = if bytes.first() == Some(&SYSEX_START) {
+        3
+    } else {
+        bytes.len().max(1)
+    };
+    interrupt::free(|cs| {
+        let mut usb = USB.borrow(cs).borrow_mut();
+        let Some(usb) = usb.as_mut() else {
+            return;
+        };
+        if usb.device.state() != UsbDeviceState::Configured {
+            usb.midi_queue.clear();
+            return;
+        }
+        // The whole message is dropped rather than truncated
+        let free = usb.midi_queue.capacity() - usb.midi_queue.len();
+        if bytes.len().div_ceil(packet_len) > free {
+            return;
+        }
+        for payload in bytes.chunks(packet_len) {
+            if let Ok(packet) =
+                UsbMidiEventPacket::try_from_payload_bytes(CableNumber::Cable0, payload)
+            {
+                let _ = usb.midi_queue.push_back(packet);
+            }
+        }
+        usb.flush_midi();
+    });
+}
+
+/// Writes text to the console, dropped if no terminal is open or the output buffer is full.
+pub fn write_console(bytes: &[u8]) {
+    interrupt::free(|cs| {
+        let mut usb = USB.borrow(cs).borrow_mut();
+        let Some(Usb {
+            device, console, ..
+        }) = usb.as_mut()
+        else {
+            return;
+        };
+        // The terminal sets DTR when it opens the port
+        if device.state() != UsbDeviceState::Configured || !console.dtr() {
+            return;
+        }
+        let _ = console.write(bytes);
+    });
+}
@@ -0,0 +1,69 @@
+use alloc::vec::Vec;
+
+/// Splits `bytes` into 7-bit bytes: each group of up to 7 bytes is preceded by a byte holding
+/// their most significant bits.
+pub fn encode_7bit(bytes: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(bytes.len().div_ceil(7) * 8);
+    for group in bytes.chunks(7) {
+        let msbs = group
+            .iter()
+            .enumerate()
+            .fold(0, |msbs, (i, b)| msbs | ((b >> 7) << i));
+        encoded.push(msbs);
+        encoded.extend(group.iter().map(|b| b & 0x7f));
+    }
+    encoded
+}
+
+/// Reverts [`encode_7bit`].
+pub fn decode_7bit(encoded: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(encoded.len() / 8 * 7 + 7);
+    for group in encoded.chunks(8) {
+        let (msbs, group) = (group[0], &group[1..]);
+        bytes.extend(
+            group
+                .iter()
+                .enumerate()
+                .map(|(i, b)| (b & 0x7f) | (((msbs >> i) & 1) << 7)),
+        );
+    }
+    bytes
+}
+
+/// Checksum of 7-bit `bytes`, the sum of the bytes and their checksum is a multiple of 128.
+pub fn checksum(bytes: &[u8]) -> u8 {
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    sum.wrapping_neg() & 0x7f
+}
+
+/// CRC-32 (IEEE 802.3) of `bytes`.
+pub fn crc32<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u32 {
+    !bytes.into_iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_7bit_round_trip() {
+        for len in [0usize, 1, 7, 8, 4096] {
+            let bytes: Vec<u8> = (0..len).map(|i| (i * 37 % 256) as u8).collect();
+            let encoded = encode_7bit(&bytes);
+            assert_eq!(encoded.len(), len + len.div_ceil(7));
+            assert!(encoded.iter().all(|b| b & 0x80 == 0));
+            assert_eq!(decode_7bit(&encoded), bytes, "length {len}");
+        }
+    }
+
+    #[test]
+    fn checksum_completes_the_sum() {
+        let bytes = [0x01, 0x7f, 0x40, 0x12];
+        let sum = bytes.iter().map(|b| *b as u32).sum::<u32>() + checksum(&bytes) as u32;
+        assert_eq!(sum % 128, 0);
+    }
+}
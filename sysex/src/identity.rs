@@ -0,0 +1,73 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::message::{DEVICE_ID, MANUFACTURER_ID, SYSEX_END, SYSEX_START};
+
+/// Universal non-real-time SysEx, which holds the identity messages.
+pub const UNIVERSAL_NON_REALTIME: u8 = 0x7e;
+/// Device ID of the universal messages addressed to every device.
+pub const ALL_CALL: u8 = 0x7f;
+
+const GENERAL_INFORMATION: u8 = 0x06;
+const IDENTITY_REQUEST: u8 = 0x01;
+const IDENTITY_REPLY: u8 = 0x02;
+// Family and model of the sequencer, least significant byte first
+const FAMILY: [u8; 2] = [DEVICE_ID, 0];
+const MODEL: [u8; 2] = [1, 0];
+
+/// Returns the Identity Request addressed to every device.
+pub fn identity_request() -> Vec<u8> {
+    vec![
+        SYSEX_START,
+        UNIVERSAL_NON_REALTIME,
+        ALL_CALL,
+        GENERAL_INFORMATION,
+        IDENTITY_REQUEST,
+        SYSEX_END,
+    ]
+}
+
+/// Returns `true` if `sysex` is an Identity Request addressed to this device.
+pub fn is_identity_request(sysex: &[u8]) -> bool {
+    matches!(
+        sysex,
+        [SYSEX_START, UNIVERSAL_NON_REALTIME, device, GENERAL_INFORMATION, IDENTITY_REQUEST, SYSEX_END]
+            if *device == ALL_CALL || *device == DEVICE_ID
+    )
+}
+
+/// Returns the Identity Reply of the device running the firmware `version`.
+pub fn identity_reply(version: [u8; 4]) -> Vec<u8> {
+    let mut sysex = vec![
+        SYSEX_START,
+        UNIVERSAL_NON_REALTIME,
+        DEVICE_ID,
+        GENERAL_INFORMATION,
+        IDENTITY_REPLY,
+        MANUFACTURER_ID,
+    ];
+    sysex.extend(FAMILY);
+    sysex.extend(MODEL);
+    sysex.extend(version.map(|b| b & 0x7f));
+    sysex.push(SYSEX_END);
+    sysex
+}
+
+/// Returns the firmware version of an Identity Reply of the device.
+pub fn parse_identity_reply(sysex: &[u8]) -> Option<[u8; 4]> {
+    match sysex {
+        [
+            SYSEX_START,
+            UNIVERSAL_NON_REALTIME,
+            _,
+            GENERAL_INFORMATION,
+            IDENTITY_REPLY,
+            MANUFACTURER_ID,
+            info @ ..,
+            SYSEX_END,
+        ] if info.len() == 8 && info[..2] == FAMILY && info[2..4] == MODEL => {
+            Some([info[4], info[5], info[6], info[7]])
+        }
+        _ => None,
+    }
+}
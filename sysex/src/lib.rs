@@ -0,0 +1,23 @@
+//! SysEx protocol used to upload tracks and firmwares to the sequencer.
+//!
+//! Every message is `F0 7D 4D <command> <data> <checksum> F7`, the data is made of 7-bit bytes.
+//! The host sends a [`Message::Begin`] (or [`Message::FirmwareBegin`]), the [`Message::Chunk`]s of
+//! the serialized track (or of the firmware image) and a [`Message::End`], and waits for the
+//! [`Message::Ack`] of each message before sending the next one. After a [`Message::Nak`] the
+//! upload must start again.
+//!
+//! The host can also read the state of the device with a [`Message::DumpRequest`], and identify
+//! it with the universal Identity Request (`F0 7E 7F 06 01 F7`).
+#![no_std]
+
+extern crate alloc;
+
+mod encoding;
+mod identity;
+mod message;
+mod receiver;
+
+pub use encoding::*;
+pub use identity::*;
+pub use message::*;
+pub use receiver::*;
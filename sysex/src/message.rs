@@ -0,0 +1,304 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use thiserror::Error;
+
+use crate::encoding::{checksum, crc32, decode_7bit, encode_7bit};
+
+pub const SYSEX_START: u8 = 0xf0;
+pub const SYSEX_END: u8 = 0xf7;
+/// Manufacturer ID reserved for non-commercial use.
+pub const MANUFACTURER_ID: u8 = 0x7d;
+pub const DEVICE_ID: u8 = 0x4d;
+
+/// Bytes of the track in a chunk, so that a chunk message fits in 64 bytes.
+pub const CHUNK_LEN: usize = 48;
+
+const BEGIN: u8 = 0x01;
+const CHUNK: u8 = 0x02;
+const END: u8 = 0x03;
+const FIRMWARE_BEGIN: u8 = 0x10;
+const DUMP_REQUEST: u8 = 0x20;
+const DUMP: u8 = 0x21;
+const ACK: u8 = 0x7e;
+const NAK: u8 = 0x7f;
+
+/// Message of the upload protocol.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Message {
+    /// Starts the upload of a track of `len` bytes into `slot`.
+    /// An empty track restores the track of the index in this slot.
+    Begin { slot: u8, len: u16 },
+    /// Starts the upload of a firmware of `len` bytes, with the CRC-32 of the whole image.
+    /// The application restarts in the bootloader which receives the rest of the upload.
+    FirmwareBegin { len: u32, crc: u32 },
+    /// Bytes of the track or firmware from `index * CHUNK_LEN`.
+    Chunk { index: u16, data: Vec<u8> },
+    /// Ends the upload, the track replaces the one of its slot or the firmware is installed.
+    End,
+    /// Asks the device for a dump of its state.
+    DumpRequest(Dump),
+    /// Reply of the device to a [`Message::DumpRequest`].
+    Dump { dump: Dump, data: Vec<u8> },
+    /// Reply of the device, the message was applied.
+    Ack,
+    /// Reply of the device, the message was rejected.
+    Nak(Nak),
+}
+
+/// Reason why the device rejected a message.
+#[derive(Error, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Nak {
+    #[error("Wrong checksum.")]
+    Checksum = 1,
+    #[error("Message out of sequence.")]
+    Sequence = 2,
+    #[error("Track too long.")]
+    TooLong = 3,
+    #[error("Invalid track, slot or firmware.")]
+    Invalid = 4,
+}
+
+/// State of the device that the host can dump.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Dump {
+    /// Pairs of setting identifier and value, like in the flash.
+    Settings = 1,
+    /// Names of the tracks in the order of the track channel settings, each followed by a 0.
+    Tracks = 2,
+    /// Firmware version, as text.
+    Version = 3,
+    /// Bytes used and free in the heap, as 32-bit little-endian numbers.
+    Heap = 4,
+}
+
+#[derive(Error, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ParseError {
+    /// The message is not addressed to this device.
+    #[error("Message of another device.")]
+    OtherDevice,
+    #[error("Wrong checksum.")]
+    Checksum,
+    #[error("Malformed message.")]
+    Malformed,
+}
+
+impl Message {
+    /// Returns the complete SysEx message.
+    pub fn to_sysex(&self) -> Vec<u8> {
+        let mut body = match self {
+            Message::Begin { slot, len } => {
+                let mut body = vec![BEGIN, slot & 0x7f];
+                body.extend(encode_u16(*len));
+                body
+            }
+            Message::FirmwareBegin { len, crc } => {
+                let mut body = vec![FIRMWARE_BEGIN];
+                body.extend(encode_u32(*len));
+                body.extend(encode_u32(*crc));
+                body
+            }
+            Message::Chunk { index, data } => {
+                let mut body = vec![CHUNK];
+                body.extend(encode_u16(*index));
+                body.extend(encode_7bit(data));
+                body
+            }
+            Message::DumpRequest(dump) => vec![DUMP_REQUEST, *dump as u8],
+            Message::Dump { dump, data } => {
+                let mut body = vec![DUMP, *dump as u8];
+                body.extend(encode_7bit(data));
+                body
+            }
+            Message::End => vec![END],
+            Message::Ack => vec![ACK],
+            Message::Nak(reason) => vec![NAK, *reason as u8],
+        };
+        body.push(checksum(&body));
+        let mut sysex = vec![SYSEX_START, MANUFACTURER_ID, DEVICE_ID];
+        sysex.append(&mut body);
+        sysex.push(SYSEX_END);
+        sysex
+    }
+
+    /// Parses a complete SysEx message.
+    pub fn parse(sysex: &[u8]) -> Result<Self, ParseError> {
+        let [SYSEX_START, manufacturer, device, body @ .., SYSEX_END] = sysex else {
+            return Err(ParseError::Malformed);
+        };
+        if (*manufacturer, *device) != (MANUFACTURER_ID, DEVICE_ID) {
+            return Err(ParseError::OtherDevice);
+        }
+        if body.iter().any(|b| b & 0x80 != 0) {
+            return Err(ParseError::Malformed);
+        }
+        let [command, data @ .., _] = body else {
+            return Err(ParseError::Malformed);
+        };
+        if checksum(body) != 0 {
+            return Err(ParseError::Checksum);
+        }
+        match (*command, data) {
+            (BEGIN, [slot, len @ ..]) => Ok(Message::Begin {
+                slot: *slot,
+                len: decode_u16(len)?,
+            }),
+            (FIRMWARE_BEGIN, data) if data.len() == 10 => Ok(Message::FirmwareBegin {
+                len: decode_u32(&data[..5])?,
+                crc: decode_u32(&data[5..])?,
+            }),
+            (CHUNK, data) if data.len() >= 3 => Ok(Message::Chunk {
+                index: decode_u16(&data[..3])?,
+                data: decode_7bit(&data[3..]),
+            }),
+            (DUMP_REQUEST, [dump]) => Ok(Message::DumpRequest(parse_dump(*dump)?)),
+            (DUMP, [dump, data @ ..]) => Ok(Message::Dump {
+                dump: parse_dump(*dump)?,
+                data: decode_7bit(data),
+            }),
+            (END, []) => Ok(Message::End),
+            (ACK, []) => Ok(Message::Ack),
+            (NAK, [reason]) => {
+                let reason = match reason {
+                    1 => Nak::Checksum,
+                    2 => Nak::Sequence,
+                    3 => Nak::TooLong,
+                    _ => Nak::Invalid,
+                };
+                Ok(Message::Nak(reason))
+            }
+            _ => Err(ParseError::Malformed),
+        }
+    }
+}
+
+/// Returns the messages uploading `track` into `slot`.
+pub fn upload(slot: u8, track: &[u8]) -> Vec<Message> {
+    let begin = Message::Begin {
+        slot,
+        len: track.len() as u16,
+    };
+    transfer(begin, track)
+}
+
+/// Returns the messages uploading the binary `image` of a firmware.
+pub fn firmware(image: &[u8]) -> Vec<Message> {
+    let begin = Message::FirmwareBegin {
+        len: image.len() as u32,
+        crc: crc32(image),
+    };
+    transfer(begin, image)
+}
+
+fn transfer(begin: Message, bytes: &[u8]) -> Vec<Message> {
+    let chunks = bytes
+        .chunks(CHUNK_LEN)
+        .enumerate()
+        .map(|(index, data)| Message::Chunk {
+            index: index as u16,
+            data: data.to_vec(),
+        });
+    core::iter::once(begin)
+        .chain(chunks)
+        .chain(core::iter::once(Message::End))
+        .collect()
+}
+
+fn parse_dump(dump: u8) -> Result<Dump, ParseError> {
+    match dump {
+        1 => Ok(Dump::Settings),
+        2 => Ok(Dump::Tracks),
+        3 => Ok(Dump::Version),
+        4 => Ok(Dump::Heap),
+        _ => Err(ParseError::Malformed),
+    }
+}
+
+// Numbers are sent as 3 bytes of 7 bits, the least significant first
+fn encode_u16(value: u16) -> [u8; 3] {
+    [
+        (value & 0x7f) as u8,
+        ((value >> 7) & 0x7f) as u8,
+        (value >> 14) as u8,
+    ]
+}
+
+// Same encoding in 5 bytes
+fn encode_u32(value: u32) -> [u8; 5] {
+    core::array::from_fn(|i| ((value >> (7 * i)) & 0x7f) as u8)
+}
+
+fn decode_u32(bytes: &[u8]) -> Result<u32, ParseError> {
+    match bytes {
+        [.., high] if bytes.len() == 5 && *high < 0x10 => Ok(bytes
+            .iter()
+            .enumerate()
+            .fold(0, |value, (i, b)| value | (*b as u32) << (7 * i))),
+        _ => Err(ParseError::Malformed),
+    }
+}
+
+fn decode_u16(bytes: &[u8]) -> Result<u16, ParseError> {
+    match bytes {
+        [low, mid, high] if *high < 4 => {
+            Ok(*low as u16 | (*mid as u16) << 7 | (*high as u16) << 14)
+        }
+        _ => Err(ParseError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_round_trip() {
+        let data: Vec<u8> = (0..=255).collect();
+        let messages = [
+            Message::Begin { slot: 3, len: 1000 },
+            Message::Begin { slot: 0, len: 0 },
+            Message::FirmwareBegin {
+                len: 0x0004_0000,
+                crc: 0xdead_beef,
+            },
+            Message::Chunk {
+                index: 0x1234,
+                data: data[..CHUNK_LEN].to_vec(),
+            },
+            Message::Chunk {
+                index: 0,
+                data: Vec::new(),
+            },
+            Message::End,
+            Message::DumpRequest(Dump::Settings),
+            Message::DumpRequest(Dump::Tracks),
+            Message::DumpRequest(Dump::Version),
+            Message::DumpRequest(Dump::Heap),
+            Message::Dump {
+                dump: Dump::Tracks,
+                data,
+            },
+            Message::Ack,
+            Message::Nak(Nak::Checksum),
+            Message::Nak(Nak::Sequence),
+            Message::Nak(Nak::TooLong),
+            Message::Nak(Nak::Invalid),
+        ];
+        for message in messages {
+            assert_eq!(Message::parse(&message.to_sysex()), Ok(message));
+        }
+    }
+
+    #[test]
+    fn parse_checksum_failure() {
+        let mut sysex = Message::Begin { slot: 1, len: 100 }.to_sysex();
+        sysex[4] ^= 0x01;
+        assert_eq!(Message::parse(&sysex), Err(ParseError::Checksum));
+    }
+
+    #[test]
+    fn parse_other_device() {
+        let mut sysex = Message::Ack.to_sysex();
+        sysex[2] = DEVICE_ID + 1;
+        assert_eq!(Message::parse(&sysex), Err(ParseError::OtherDevice));
+    }
+}
@@ -0,0 +1,106 @@
+use alloc::vec::Vec;
+
+use crate::message::{CHUNK_LEN, Message, Nak};
+
+// Upload in progress
+struct Upload {
+    slot: u8,
+    len: usize,
+    data: Vec<u8>,
+}
+
+/// Reassembles the tracks uploaded to the device.
+pub struct Receiver {
+    max_len: usize,
+    upload: Option<Upload>,
+}
+
+impl Receiver {
+    /// Tracks longer than `max_len` bytes are rejected.
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            upload: None,
+        }
+    }
+
+    /// Applies a message of the host.
+    /// Returns the slot and the bytes of the track when its upload ends, the upload is dropped
+    /// if the message is rejected.
+    pub fn handle(&mut self, message: Message) -> Result<Option<(u8, Vec<u8>)>, Nak> {
+        let result = match (message, self.upload.as_mut()) {
+            (Message::Begin { len, .. }, _) if len as usize > self.max_len => Err(Nak::TooLong),
+            (Message::Begin { slot, len }, _) => {
+                self.upload = Some(Upload {
+                    slot,
+                    len: len as usize,
+                    data: Vec::with_capacity(len as usize),
+                });
+                return Ok(None);
+            }
+            (Message::Chunk { index, data }, Some(upload))
+                if index as usize * CHUNK_LEN == upload.data.len()
+                    && upload.data.len() + data.len() <= upload.len =>
+            {
+                upload.data.extend(data);
+                return Ok(None);
+            }
+            // The host sends the last chunk again when its acknowledgement is lost
+            (Message::Chunk { index, data }, Some(upload))
+                if !data.is_empty()
+                    && upload.data.get(index as usize * CHUNK_LEN..) == Some(&data[..]) =>
+            {
+                return Ok(None);
+            }
+            (Message::End, Some(upload)) if upload.data.len() == upload.len => {
+                Ok(self.upload.take().map(|u| (u.slot, u.data)))
+            }
+            _ => Err(Nak::Sequence),
+        };
+        if result.is_err() {
+            self.upload = None;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn repeated_chunk() {
+        let mut receiver = Receiver::new(100);
+        let data: Vec<u8> = (0..60).collect();
+        let chunk = |index: u16| Message::Chunk {
+            index,
+            data: data.chunks(CHUNK_LEN).nth(index as usize).unwrap().to_vec(),
+        };
+        assert_eq!(
+            receiver.handle(Message::Begin { slot: 2, len: 60 }),
+            Ok(None)
+        );
+        assert_eq!(receiver.handle(chunk(0)), Ok(None));
+        assert_eq!(receiver.handle(chunk(0)), Ok(None));
+        assert_eq!(receiver.handle(chunk(1)), Ok(None));
+        assert_eq!(receiver.handle(chunk(1)), Ok(None));
+        assert_eq!(receiver.handle(Message::End), Ok(Some((2, data))));
+    }
+
+    #[test]
+    fn chunk_out_of_sequence() {
+        let mut receiver = Receiver::new(100);
+        assert_eq!(
+            receiver.handle(Message::Begin { slot: 2, len: 60 }),
+            Ok(None)
+        );
+        let chunk = Message::Chunk {
+            index: 1,
+            data: vec![0; 12],
+        };
+        assert_eq!(receiver.handle(chunk), Err(Nak::Sequence));
+        assert_eq!(receiver.handle(Message::End), Err(Nak::Sequence));
+    }
+}
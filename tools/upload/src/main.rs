@@ -0,0 +1,299 @@
+//! Uploads a track of the index, or a firmware, to the sequencer over MIDI SysEx.
+//!
+//! The track is loaded from its CSV file like in `kernel/build.rs`, serialized with postcard and
+//! split into the messages of the `sysex` crate. A firmware is the binary image of the kernel, as
+//! built by `make update` (`cargo objcopy --release -- -O binary mseq.bin`). The messages are
+//! written to a `.syx` file, or sent to an ALSA port while waiting for the reply of the sequencer
+//! to each one.
+//!
+//! `--identity` and `--dump` print the identity or a dump of the state of the sequencer connected
+//! to the port.
+
+use mseq_core::Track;
+use mseq_tracks::index::load_from_file;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use sysex::{Dump, Message};
+
+const USAGE: &str = "Usage: upload (<track> [--index <index.toml>] [--slot <slot>] [--restore] \
+                     | --firmware <mseq.bin>) (--out <file.syx> | --port <port>)\n       \
+                     upload (--identity | --dump <settings|tracks|version|heap>) --port <port>";
+// Time to wait for the reply of the sequencer to a message, the bootloader erases the flash
+// before writing some chunks
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+// The application does not reply to the start of a firmware upload, it restarts in the bootloader
+// which replies to the next attempt
+const FIRMWARE_BEGIN_TIMEOUT: Duration = Duration::from_secs(1);
+const FIRMWARE_BEGIN_ATTEMPTS: usize = 5;
+const CLIENT_NAME: &str = "mseq upload";
+
+enum Output {
+    File(PathBuf),
+    Port(String),
+}
+
+// What is read from the sequencer instead of an upload
+enum Query {
+    Identity,
+    Dump(Dump),
+}
+
+struct Args {
+    track: Option<String>,
+    index: PathBuf,
+    slot: Option<u8>,
+    // Restores the track of the index built in the firmware
+    restore: bool,
+    firmware: Option<PathBuf>,
+    query: Option<Query>,
+    output: Output,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let mut track = None;
+    let mut index = PathBuf::from("res/index.toml");
+    let mut slot = None;
+    let mut restore = false;
+    let mut firmware = None;
+    let mut query = None;
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value of {arg}"));
+        match arg.as_str() {
+            "--index" => index = PathBuf::from(value()?),
+            "--slot" => slot = Some(value()?.parse().map_err(|e| format!("Invalid slot: {e}"))?),
+            "--restore" => restore = true,
+            "--firmware" => firmware = Some(PathBuf::from(value()?)),
+            "--identity" => query = Some(Query::Identity),
+            "--dump" => {
+                let dump = match value()?.as_str() {
+                    "settings" => Dump::Settings,
+                    "tracks" => Dump::Tracks,
+                    "version" => Dump::Version,
+                    "heap" => Dump::Heap,
+                    dump => return Err(format!("Unknown dump {dump}")),
+                };
+                query = Some(Query::Dump(dump));
+            }
+            "--out" => output = Some(Output::File(PathBuf::from(value()?))),
+            "--port" => output = Some(Output::Port(value()?)),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {arg}")),
+            _ => track = Some(arg),
+        }
+    }
+    if [track.is_some(), firmware.is_some(), query.is_some()]
+        .iter()
+        .filter(|&&given| given)
+        .count()
+        != 1
+    {
+        return Err("Expected a track, a firmware or a query".to_string());
+    }
+    Ok(Args {
+        track,
+        index,
+        slot,
+        restore,
+        firmware,
+        query,
+        output: output.ok_or("Missing output")?,
+    })
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = parse_args().map_err(|e| format!("{e}\n{USAGE}"))?;
+    if let Some(query) = &args.query {
+        let Output::Port(name) = &args.output else {
+            return Err(format!("A query needs a port\n{USAGE}").into());
+        };
+        return print_query(name, query);
+    }
+    let (messages, description) = match (&args.firmware, &args.track) {
+        (Some(path), _) => {
+            let image = fs::read(path)?;
+            (sysex::firmware(&image), "Firmware installed".to_string())
+        }
+        (None, Some(name)) => track_messages(&args, name)?,
+        (None, None) => unreachable!(),
+    };
+
+    match args.output {
+        Output::File(path) => {
+            let mut file = File::create(&path)?;
+            for message in &messages {
+                file.write_all(&message.to_sysex())?;
+            }
+            println!("{} messages written to {}", messages.len(), path.display());
+        }
+        Output::Port(name) => {
+            send(&name, &messages)?;
+            println!("{description}");
+        }
+    }
+    Ok(())
+}
+
+// Returns the messages uploading the track `name` of the index, with a description of the upload
+fn track_messages(args: &Args, name: &str) -> Result<(Vec<Message>, String), Box<dyn Error>> {
+    // The slots are the tracks of the index, in the order of the table generated by the kernel
+    let tracks = load_from_file(&args.index)?;
+    let (slot, (track, _)) = tracks
+        .iter()
+        .enumerate()
+        .find(|(_, (t, _))| t.get_name() == name)
+        .ok_or(format!("No track {name} in {}", args.index.display()))?;
+    let slot = args.slot.unwrap_or(slot as u8);
+    let bytes = if args.restore {
+        vec![]
+    } else {
+        postcard::to_stdvec(track)?
+    };
+    Ok((
+        sysex::upload(slot, &bytes),
+        format!("Track {name} uploaded to slot {slot}"),
+    ))
+}
+
+// Input connection kept open, output connection and SysEx messages received
+type Connection = (
+    MidiInputConnection<()>,
+    MidiOutputConnection,
+    Receiver<Vec<u8>>,
+);
+
+// Connects to the first port whose name contains `name`, the SysEx messages received are sent to
+// the returned channel
+fn connect(name: &str) -> Result<Connection, Box<dyn Error>> {
+    let mut input = MidiInput::new(CLIENT_NAME)?;
+    // The replies are SysEx messages
+    input.ignore(Ignore::None);
+    let input_port = input
+        .ports()
+        .into_iter()
+        .find(|p| input.port_name(p).is_ok_and(|n| n.contains(name)))
+        .ok_or(format!("No input port {name}"))?;
+    let output = MidiOutput::new(CLIENT_NAME)?;
+    let output_port = output
+        .ports()
+        .into_iter()
+        .find(|p| output.port_name(p).is_ok_and(|n| n.contains(name)))
+        .ok_or(format!("No output port {name}"))?;
+
+    let (replies, reply) = mpsc::channel();
+    let input = input.connect(
+        &input_port,
+        CLIENT_NAME,
+        move |_, bytes, _| {
+            if bytes.first() == Some(&sysex::SYSEX_START) {
+                let _ = replies.send(bytes.to_vec());
+            }
+        },
+        (),
+    )?;
+    let output = output.connect(&output_port, CLIENT_NAME)?;
+    Ok((input, output, reply))
+}
+
+// Waits for the next message of the protocol, the other SysEx messages are skipped
+fn recv_message(reply: &Receiver<Vec<u8>>, timeout: Duration) -> Result<Message, RecvTimeoutError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let bytes = reply.recv_timeout(deadline.saturating_duration_since(Instant::now()))?;
+        if let Ok(message) = Message::parse(&bytes) {
+            return Ok(message);
+        }
+    }
+}
+
+// Sends the messages to the port `name`, one at a time
+fn send(name: &str, messages: &[Message]) -> Result<(), Box<dyn Error>> {
+    let (_input, mut output, reply) = connect(name)?;
+
+    for (i, message) in messages.iter().enumerate() {
+        let (timeout, attempts) = match message {
+            Message::FirmwareBegin { .. } => (FIRMWARE_BEGIN_TIMEOUT, FIRMWARE_BEGIN_ATTEMPTS),
+            _ => (REPLY_TIMEOUT, 1),
+        };
+        let mut answer = Err(RecvTimeoutError::Timeout);
+        for _ in 0..attempts {
+            output.send(&message.to_sysex())?;
+            answer = recv_message(&reply, timeout);
+            if answer.is_ok() {
+                break;
+            }
+        }
+        match answer {
+            Ok(Message::Ack) => {}
+            Ok(Message::Nak(reason)) => {
+                return Err(format!("Message {i} rejected: {reason}").into());
+            }
+            _ => return Err(format!("No reply to message {i}").into()),
+        }
+    }
+    Ok(())
+}
+
+// Prints the identity or a dump of the sequencer connected to the port `name`
+fn print_query(name: &str, query: &Query) -> Result<(), Box<dyn Error>> {
+    let (_input, mut output, reply) = connect(name)?;
+    match query {
+        Query::Identity => {
+            output.send(&sysex::identity_request())?;
+            let deadline = Instant::now() + REPLY_TIMEOUT;
+            let version = loop {
+                let bytes =
+                    reply.recv_timeout(deadline.saturating_duration_since(Instant::now()))?;
+                if let Some(version) = sysex::parse_identity_reply(&bytes) {
+                    break version;
+                }
+            };
+            println!("mseq {}.{}.{}", version[0], version[1], version[2]);
+        }
+        Query::Dump(dump) => {
+            output.send(&Message::DumpRequest(*dump).to_sysex())?;
+            let data = match recv_message(&reply, REPLY_TIMEOUT)? {
+                Message::Dump { dump: d, data } if d == *dump => data,
+                _ => return Err("Unexpected reply".into()),
+            };
+            match dump {
+                Dump::Settings => {
+                    for &[id, value] in data.as_chunks::<2>().0 {
+                        println!("{id:#04x}: {value}");
+                    }
+                }
+                Dump::Tracks => {
+                    let names: Vec<_> = data
+                        .split(|&b| b == 0)
+                        .filter(|n| !n.is_empty())
+                        .map(String::from_utf8_lossy)
+                        .collect();
+                    // The demo track comes first and the recorded track last, the upload slots
+                    // are the tracks of the index in between
+                    for (i, name) in names.iter().enumerate() {
+                        if i == 0 || i == names.len() - 1 {
+                            println!("-: {name}");
+                        } else {
+                            println!("{}: {name}", i - 1);
+                        }
+                    }
+                }
+                Dump::Version => println!("{}", String::from_utf8_lossy(&data)),
+                Dump::Heap => {
+                    let [used, free] = [0, 4].map(|i| {
+                        data.get(i..i + 4)
+                            .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+                    });
+                    println!("Heap: {used} bytes used, {free} bytes free");
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,13 +1,51 @@
+use alloc::string::{String, ToString};
+use alloc::vec;
 use alloc::vec::Vec;
-use alloc::{format, vec};
-use log::{debug, trace};
+use log::{trace, warn};
 use mseq_core::*;
 use postcard::from_bytes;
 
+use crate::learn::MidiLearn;
+use crate::mixer::{MIXER_MAP, Mixer, TrackState};
+use crate::nrpn::{Nrpn, Param};
+use crate::pages::{Diagnostics, Page, line};
+use crate::recorder::{RECORD_NAME, Recorder};
+use crate::scenes::SCENES;
+use crate::settings::{Setting, Settings};
+use crate::song::{SONG, SongPlayer};
+use crate::tracks::{self, TRACKS, TrackError};
+use crate::transpose::Transposer;
+use driver::{PanelEvent, Store};
+
 struct MyTrack {
     channel_id: u8,
 }
-const ACID_TRACK: &[u8] = include_bytes!("../../track_bin/acid.bin");
+
+// Midi channel and controller used to control the device itself
+pub(crate) const CONTROL_CHANNEL: u8 = 16;
+const NEXT_PAGE_CC: u8 = 102;
+// Values from 64 start the recording, lower values stop it
+const RECORD_CC: u8 = 103;
+const CLEAR_RECORD_CC: u8 = 104;
+// Jumps to the next part of the song at the next bar
+const NEXT_PART_CC: u8 = 105;
+// Learns the controller of the parameter at this index of `Param::all`, other values cancel
+const LEARN_CC: u8 = 106;
+const CLEAR_LEARN_CC: u8 = 107;
+
+/// Key of the settings in the persistent store.
+pub const SETTINGS_KEY: u16 = 1;
+/// Key of the recorded track in the persistent store.
+pub const RECORDING_KEY: u16 = 2;
+/// Key of the midi learn mapping in the persistent store.
+pub const LEARN_KEY: u16 = 3;
+/// Key of the track uploaded into the first slot, the other slots follow.
+pub const UPLOAD_KEY: u16 = 0x100;
+
+// Steps between two sixteenth notes
+const SIXTEENTH: u32 = 6;
+// Scene changes are delayed to the start of the next bar
+const BAR: u32 = 96;
 
 // Implement a track for full freedom (randomization, automatization...)
 impl Track for MyTrack {
@@ -35,11 +73,47 @@ impl Track for MyTrack {
             vec![]
         }
     }
+
+    fn get_name(&self) -> String {
+        "demo".to_string()
+    }
 }
 
 pub struct UserConductor {
     track: MyTrack,
-    acid: DeteTrack,
+    // Tracks of the index, in the order of `TRACKS`
+    tracks: Vec<DeteTrack>,
+    // Serialized tracks uploaded in place of the tracks of the index, by slot
+    uploads: Vec<Option<Vec<u8>>>,
+    page: Page,
+    settings: Settings,
+    // Instructions delayed by the swing, with the step at which they are played
+    swung: Vec<(u32, Instruction)>,
+    // Every track plays until a scene is selected
+    scene: Option<usize>,
+    next_scene: Option<usize>,
+    // Tracks of the current scene, by index
+    active: Vec<bool>,
+    mixer: Mixer,
+    // Notes being played by each track, released when the track is muted
+    held: Vec<Vec<HeldNote>>,
+    transposer: Transposer,
+    // Played after the tracks of the index
+    recorder: Recorder,
+    song: SongPlayer,
+    // Step of the last update
+    last_step: Option<u32>,
+    // Values of the NRPN parameters last sent on the midi output, in the order of `Param::all`
+    echoed: Vec<u8>,
+    learn: MidiLearn,
+}
+
+#[derive(Clone, Copy)]
+struct HeldNote {
+    midi_note: MidiNote,
+    channel_id: u8,
+    // Step at which the note ends
+    end: u32,
 }
 
 impl Conductor for UserConductor {
@@ -53,41 +127,226 @@ impl Conductor for UserConductor {
     fn update(&mut self, context: &mut mseq_core::Context) -> Vec<Instruction> {
         let step = context.get_step();
 
-        // Quit after 960 steps
-        if step == 959 {
-            context.quit();
-            return vec![];
+        // The update runs at every clock tick, the step stays the same while the sequencer is
+        // stopped and goes back when it is started from the beginning. Nothing is played while
+        // the step stays the same, and the instructions returned are dropped by `mseq_core`.
+        let new_step = self.last_step != Some(step);
+        let restarted = self.last_step.is_none_or(|last| step < last);
+        self.last_step = Some(step);
+        if !new_step {
+            return Vec::new();
+        }
+        // The swung notes of the previous run would fire at once
+        if restarted {
+            self.swung.clear();
+        }
+
+        // The song selects the scene of each part, the first part starts right away
+        if self.settings.song_mode && restarted {
+            if let Some(scene) = self.song.restart(&SONG) {
+                self.next_scene = None;
+                self.set_scene(scene);
+            }
+        } else if self.settings.song_mode
+            && step.is_multiple_of(BAR)
+            && let Some(scene) = self.song.bar(&SONG)
+        {
+            self.next_scene = Some(scene);
+        }
+
+        if step.is_multiple_of(BAR)
+            && let Some(scene) = self.next_scene.take()
+        {
+            self.set_scene(scene);
         }
 
-        // The conductor plays the track
-        let mut instructions = self.track.play_step(step);
-        instructions.extend(self.acid.play_step(step));
+        if let Some(key) = self
+            .transposer
+            .update(step, self.settings.transpose_quantize)
+        {
+            self.tracks
+                .iter_mut()
+                .zip(TRACKS)
+                .filter(|(_, entry)| entry.kind.has_root())
+                .for_each(|(track, _)| track.transpose(key));
+        }
+
+        // Tracks muted by NRPN release their notes here
+        let released = self.release_silenced();
+
+        // The odd sixteenth notes are delayed by the swing
+        let delay = (self.settings.swing as u32).saturating_sub(50) * 2 * SIXTEENTH / 100;
+        let swung = delay > 0 && step % (2 * SIXTEENTH) == SIXTEENTH;
+        let start = if swung { step + delay } else { step };
+
+        // The conductor plays the audible tracks of the scene on the channels chosen in the
+        // settings
+        self.held
+            .iter_mut()
+            .for_each(|notes| notes.retain(|n| n.end > step));
+        let mut played = Vec::new();
+        self.recorder.update(step, self.settings.record_mode);
+        core::iter::once(self.track.play_step(step))
+            .chain(self.tracks.iter_mut().map(|t| t.play_step(step)))
+            .chain(core::iter::once(self.recorder.track_mut().play_step(step)))
+            .zip(self.settings.track_channels)
+            .zip(self.held.iter_mut())
+            .enumerate()
+            .filter(|(i, _)| self.active[*i] && self.mixer.audible(*i))
+            .for_each(|(_, ((instructions, channel), held))| {
+                instructions
+                    .into_iter()
+                    .map(|i| with_channel(i, channel))
+                    .for_each(|instruction| {
+                        if let Instruction::PlayNote {
+                            midi_note,
+                            len,
+                            channel_id,
+                        } = instruction
+                        {
+                            held.push(HeldNote {
+                                midi_note,
+                                channel_id,
+                                end: start + len,
+                            });
+                        }
+                        played.push(instruction);
+                    })
+            });
+
+        if swung {
+            self.swung.extend(played.drain(..).map(|i| (start, i)));
+        }
+        let mut instructions = released;
+        instructions.extend(played);
+        self.swung.retain(|&(due, instruction)| {
+            if due <= step {
+                instructions.push(instruction);
+            }
+            due > step
+        });
 
-        // instructions
         instructions
     }
 
     fn handle_input(
         &mut self,
         input: mseq_core::MidiMessage,
-        _context: &Context,
+        context: &Context,
     ) -> Vec<Instruction> {
+        if self.mixer.handle(&MIXER_MAP, &input) {
+            return self.release_silenced();
+        }
+
         match input {
-            mseq_core::MidiMessage::NoteOff { channel, note } => {
-                vec![Instruction::MidiMessage {
-                    midi_message: MidiMessage::NoteOff {
-                        channel,
-                        note: note.transpose(3),
-                    },
-                }]
-            }
-            mseq_core::MidiMessage::NoteOn { channel, note } => {
-                vec![Instruction::MidiMessage {
-                    midi_message: MidiMessage::NoteOn {
-                        channel,
-                        note: note.transpose(3),
-                    },
-                }]
+            // Keys of the transposition keyboard
+            mseq_core::MidiMessage::NoteOn { channel, note }
+                if channel == self.settings.transpose_channel =>
+            {
+                self.transposer.key_on(note.note);
+                vec![]
+            }
+            mseq_core::MidiMessage::NoteOff { channel, note }
+                if channel == self.settings.transpose_channel =>
+            {
+                self.transposer
+                    .key_off(note.note, self.settings.transpose_latch);
+                vec![]
+            }
+            // Notes recorded into the recorded track
+            mseq_core::MidiMessage::NoteOn { channel, note }
+                if self.recorder.is_armed() && !is_control(&input) =>
+            {
+                let grid = self.settings.record_grid_steps();
+                self.recorder
+                    .note_on(note, channel, context.get_step(), grid);
+                self.thru(input)
+            }
+            mseq_core::MidiMessage::NoteOff { note, .. }
+                if self.recorder.is_armed() && !is_control(&input) =>
+            {
+                let grid = self.settings.record_grid_steps();
+                self.recorder.note_off(note, context.get_step(), grid);
+                self.thru(input)
+            }
+            // Forward everything but the messages controlling the device
+            mseq_core::MidiMessage::NoteOff { .. }
+            | mseq_core::MidiMessage::NoteOn { .. }
+            | mseq_core::MidiMessage::CC { .. }
+            | mseq_core::MidiMessage::PC { .. }
+                if !is_control(&input) =>
+            {
+                self.thru(input)
+            }
+            mseq_core::MidiMessage::PC {
+                channel: CONTROL_CHANNEL,
+                value,
+            } => {
+                if (value as usize) < SCENES.len() {
+                    self.next_scene = Some(value as usize);
+                } else {
+                    warn!("No scene for program {value}");
+                }
+                vec![]
+            }
+            mseq_core::MidiMessage::CC {
+                channel: CONTROL_CHANNEL,
+                controller: RECORD_CC,
+                value,
+            } => {
+                self.recorder.arm(value >= 64);
+                vec![]
+            }
+            mseq_core::MidiMessage::CC {
+                channel: CONTROL_CHANNEL,
+                controller: CLEAR_RECORD_CC,
+                value,
+            } => {
+                if value > 0 {
+                    self.recorder.clear();
+                }
+                vec![]
+            }
+            mseq_core::MidiMessage::CC {
+                channel: CONTROL_CHANNEL,
+                controller: NEXT_PART_CC,
+                value,
+            } => {
+                if value > 0 {
+                    self.song.next_part();
+                }
+                vec![]
+            }
+            mseq_core::MidiMessage::CC {
+                channel: CONTROL_CHANNEL,
+                controller: LEARN_CC,
+                value,
+            } => {
+                match Param::from_id(value) {
+                    Some(param) => self.learn.start(param),
+                    None => self.learn.cancel(),
+                }
+                vec![]
+            }
+            mseq_core::MidiMessage::CC {
+                channel: CONTROL_CHANNEL,
+                controller: CLEAR_LEARN_CC,
+                value,
+            } => {
+                if value > 0 {
+                    self.learn.clear();
+                }
+                vec![]
+            }
+            mseq_core::MidiMessage::CC {
+                channel: CONTROL_CHANNEL,
+                controller: NEXT_PAGE_CC,
+                value,
+            } => {
+                if value > 0 {
+                    self.next_page();
+                }
+                vec![]
             }
             _ => vec![],
         }
@@ -96,27 +355,447 @@ impl Conductor for UserConductor {
 
 impl Default for UserConductor {
     fn default() -> Self {
-        let c = Self {
-            acid: from_bytes(ACID_TRACK).unwrap(),
+        let mut c = Self {
+            tracks: TRACKS
+                .iter()
+                .map(|t| from_bytes(t.bytes).unwrap())
+                .collect(),
+            uploads: vec![None; TRACKS.len()],
             track: MyTrack { channel_id: 1 },
+            page: Page::default(),
+            settings: Settings::default(),
+            swung: Vec::new(),
+            scene: None,
+            next_scene: None,
+            active: Vec::new(),
+            mixer: Mixer::default(),
+            held: Vec::new(),
+            transposer: Transposer::default(),
+            recorder: Recorder::default(),
+            song: SongPlayer::default(),
+            last_step: None,
+            echoed: Vec::new(),
+            learn: MidiLearn::default(),
         };
-        //trace!("{:?}", c.acid);
+        c.active = vec![true; c.track_count()];
+        c.held = vec![Vec::new(); c.track_count()];
+        c.echoed = Param::all().map(|p| c.param_value(p)).collect();
         c
     }
 }
 
 impl UserConductor {
-    pub fn display_text(&self, context: &Context) -> driver::DisplayText {
-        let line0 = heapless::String::try_from(" -- Mseq -- ").unwrap();
-        let line1 =
-            heapless::String::try_from(format!("Bpm: {}", context.get_bpm()).as_str()).unwrap();
-        let line2 =
-            heapless::String::try_from(format!("Step: {}", context.get_step() / 24).as_str())
-                .unwrap();
-        let line3 = heapless::String::try_from("Machines play").unwrap();
+    /// Switches the display to the next page.
+    pub fn next_page(&mut self) {
+        self.page = self.page.next();
+    }
+
+    /// Handles an event from the front panel controls.
+    /// Changes made to `context` are applied at the next clock tick.
+    pub fn handle_panel(&mut self, event: PanelEvent, context: &mut Context) {
+        match event {
+            PanelEvent::ButtonPress {
+                id: driver::ENCODER_BUTTON,
+            } => self.next_page(),
+            PanelEvent::EncoderTurn {
+                id: driver::MAIN_ENCODER,
+                delta,
+            } if self.page == Page::Transport => {
+                self.settings.step(Setting::Bpm, delta);
+                self.apply_setting(Setting::Bpm, context);
+            }
+            _ => (),
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// The changes must be applied with [`UserConductor::apply_setting`].
+    pub fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
+    /// Applies the current value of `setting`.
+    /// Settings used by the kernel (e.g. the clock mode) are read by the kernel itself.
+    pub fn apply_setting(&mut self, setting: Setting, context: &mut Context) {
+        // The other settings are read where they are used
+        if setting == Setting::Bpm {
+            context.set_bpm(self.settings.bpm);
+        }
+    }
+
+    /// Restores the settings, the midi learn mapping and the recorded track saved in `store`.
+    pub fn load(&mut self, store: &impl Store, context: &mut Context) {
+        if let Some(bytes) = store.read(SETTINGS_KEY) {
+            self.settings.load(bytes);
+            context.set_bpm(self.settings.bpm);
+        }
+        if let Some(bytes) = store.read(LEARN_KEY) {
+            self.learn.load(bytes);
+        }
+        if let Some(bytes) = store.read(RECORDING_KEY)
+            && let Err(e) = self.recorder.load(bytes)
+        {
+            warn!("Saved recording ignored: {e}");
+        }
+        for slot in 0..TRACKS.len() {
+            // A saved track that fails the checks of `load_track` is removed at the next save
+            if let Some(bytes) = store.read(UPLOAD_KEY + slot as u16)
+                && let Err(e) = self.load_track(slot, bytes)
+            {
+                warn!("Saved track of slot {slot} ignored: {e}");
+            }
+        }
+    }
+
+    /// Returns the values to save in the persistent store, by key.
+    /// The recorded track is left out while it is being recorded.
+    pub fn saved_state(&self) -> Vec<(u16, Vec<u8>)> {
+        let mut state = vec![
+            (SETTINGS_KEY, self.settings.to_bytes()),
+            (LEARN_KEY, self.learn.to_bytes()),
+        ];
+        if !self.recorder.is_armed() {
+            state.push((RECORDING_KEY, self.recorder.to_bytes()));
+        }
+        // The slots without upload remove their saved track
+        state.extend(
+            self.uploads
+                .iter()
+                .enumerate()
+                .map(|(slot, bytes)| (UPLOAD_KEY + slot as u16, bytes.clone().unwrap_or_default())),
+        );
+        state
+    }
+
+    /// Replaces the track of the index in `slot` by a `DeteTrack` serialized with postcard.
+    /// Empty `bytes` restore the track of the index. A track that cannot be played is rejected
+    /// before it is stored.
+    pub fn load_track(&mut self, slot: usize, bytes: &[u8]) -> Result<(), TrackError> {
+        let entry = TRACKS.get(slot).ok_or(TrackError::Slot(slot))?;
+        let bytes_or_entry = if bytes.is_empty() { entry.bytes } else { bytes };
+        tracks::validate(bytes_or_entry)?;
+        let mut track: DeteTrack = from_bytes(bytes_or_entry)?;
+        if entry.kind.has_root() {
+            track.transpose(self.transposer.key());
+        }
+        self.tracks[slot] = track;
+        self.uploads[slot] = (!bytes.is_empty()).then(|| bytes.to_vec());
+        Ok(())
+    }
+
+    /// Applies a parameter received by NRPN.
+    /// Changes made to `context` are applied at the next clock tick.
+    pub fn handle_nrpn(&mut self, nrpn: Nrpn, context: &mut Context) {
+        trace!("NRPN {:?}: {}", nrpn.param, nrpn.data());
+        self.set_param(nrpn.param, nrpn.data(), context);
+    }
+
+    /// Learns the controller of `input` if a parameter waits for one, or applies the parameter
+    /// mapped to it. Returns `false` if `input` is not a learned controller.
+    /// Changes made to `context` are applied at the next clock tick.
+    pub fn handle_learned(&mut self, input: &MidiMessage, context: &mut Context) -> bool {
+        match self.learn.handle(input) {
+            Some(Some((param, value))) => {
+                self.set_param(param, param.scale(value), context);
+                true
+            }
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    pub fn learn(&self) -> &MidiLearn {
+        &self.learn
+    }
+
+    pub fn learn_mut(&mut self) -> &mut MidiLearn {
+        &mut self.learn
+    }
+
+    // Sets `param` from the midi input
+    fn set_param(&mut self, param: Param, value: u8, context: &mut Context) {
+        match param {
+            Param::Bpm => {
+                self.settings.set(Setting::Bpm, value);
+                self.apply_setting(Setting::Bpm, context);
+            }
+            Param::Swing => self.settings.set(Setting::Swing, value),
+            Param::Transpose => self
+                .transposer
+                .set_key(value.checked_sub(1).map(|n| Note::from(n % 12))),
+            // The notes of the track are released at the next step
+            Param::Mute(track) => self.mixer.set_muted(track, value >= 64),
+            Param::Channel(track) => self.settings.set(Setting::TrackChannel(track), value),
+        }
+    }
+
+    /// Returns the messages sending the NRPN parameters that changed since the last call, from
+    /// any control, if the echo is enabled.
+    pub fn nrpn_echo(&mut self) -> Vec<MidiMessage> {
+        if !self.settings.nrpn_echo {
+            return vec![];
+        }
+        let values: Vec<u8> = Param::all().map(|p| self.param_value(p)).collect();
+        let messages = Param::all()
+            .zip(values.iter().zip(&self.echoed))
+            .filter(|(_, (value, echoed))| value != echoed)
+            .flat_map(|(param, (value, _))| param.messages(*value))
+            .collect();
+        self.echoed = values;
+        messages
+    }
+
+    /// Returns the mixer, e.g. to show the state of the tracks.
+    pub fn mixer(&self) -> &Mixer {
+        &self.mixer
+    }
+
+    pub fn track_count(&self) -> usize {
+        2 + self.tracks.len()
+    }
+
+    /// Returns the recorder, e.g. to persist the recorded track.
+    pub fn recorder_mut(&mut self) -> &mut Recorder {
+        &mut self.recorder
+    }
+
+    // Current value of an NRPN parameter
+    fn param_value(&self, param: Param) -> u8 {
+        match param {
+            Param::Bpm => self.settings.bpm,
+            Param::Swing => self.settings.swing,
+            Param::Transpose => self
+                .transposer
+                .next_key()
+                .map_or(0, |note| u8::from(note) + 1),
+            Param::Mute(track) => self.mixer.is_muted(track) as u8 * 127,
+            Param::Channel(track) => self.settings.get(Setting::TrackChannel(track)),
+        }
+    }
+
+    // Forwards `input` to the midi output if the thru is enabled
+    fn thru(&self, input: MidiMessage) -> Vec<Instruction> {
+        if self.settings.thru {
+            vec![Instruction::MidiMessage {
+                midi_message: input,
+            }]
+        } else {
+            vec![]
+        }
+    }
+
+    // Releases the notes of the tracks that are not audible anymore, only the audible tracks hold
+    // notes
+    fn release_silenced(&mut self) -> Vec<Instruction> {
+        let mixer = &self.mixer;
+        self.held
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| !mixer.audible(*i))
+            .flat_map(|(_, held)| held.drain(..))
+            .map(|n| Instruction::StopNote {
+                midi_note: n.midi_note,
+                channel_id: n.channel_id,
+            })
+            .collect()
+    }
+
+    // Plays the tracks of scene `index` from now on
+    fn set_scene(&mut self, index: usize) {
+        let scene = &SCENES[index];
+        trace!("Scene {}", scene.name);
+        self.active = self
+            .track_names()
+            .iter()
+            // The recorded track is not part of the scenes
+            .map(|name| scene.tracks.contains(&name.as_str()) || name == RECORD_NAME)
+            .collect();
+        self.scene = Some(index);
+    }
+
+    fn scene_name(scene: Option<usize>) -> &'static str {
+        scene.map_or("all", |i| SCENES[i].name)
+    }
+
+    /// Names of the tracks, in the order of the track channel settings.
+    pub fn track_names(&self) -> Vec<String> {
+        core::iter::once(self.track.get_name())
+            .chain(self.tracks.iter().map(|t| t.get_name()))
+            .chain(core::iter::once(self.recorder.track().get_name()))
+            .collect()
+    }
+
+    pub fn display_text(
+        &self,
+        context: &Context,
+        diagnostics: &Diagnostics,
+    ) -> driver::DisplayText {
+        match self.page {
+            Page::Transport => self.transport_page(context),
+            Page::Tracks => self.tracks_page(),
+            Page::Mixer => self.mixer_page(),
+            Page::Diagnostics => Self::diagnostics_page(diagnostics),
+        }
+    }
+
+    fn transport_page(&self, context: &Context) -> driver::DisplayText {
+        let line0 = if self.settings.song_mode && !SONG.parts.is_empty() {
+            let (part, bar) = self.song.position();
+            line(format_args!(
+                "Part {}/{} Bar {}/{}",
+                part + 1,
+                SONG.parts.len(),
+                bar + 1,
+                SONG.parts[part].bars
+            ))
+        } else {
+            line(format_args!(" -- Mseq -- "))
+        };
+        let line1 = match self.transposer.key() {
+            Some(key) => line(format_args!("Bpm: {} Key: {}", context.get_bpm(), key)),
+            None => line(format_args!("Bpm: {}", context.get_bpm())),
+        };
+        let rec = if self.recorder.is_armed() { " REC" } else { "" };
+        let line2 = line(format_args!("Step: {}{rec}", context.get_step() / 24));
+        let line3 = match self.next_scene {
+            Some(next) => line(format_args!(
+                "Scene: {} > {}",
+                Self::scene_name(self.scene),
+                Self::scene_name(Some(next))
+            )),
+            None => line(format_args!("Scene: {}", Self::scene_name(self.scene))),
+        };
+
+        // One cell per sixteenth note of the first bar of the first track of the index
+        let steps = self
+            .tracks
+            .first()
+            .map_or_else(heapless::Vec::new, |track| {
+                (0..16)
+                    .map(|i| !track.get_notes_start_at_step(i * 6).is_empty())
+                    .collect()
+            });
+        let current = ((context.get_step() / 6) % 16) as u8;
 
         driver::DisplayText {
             lines: [line0, line1, line2, line3],
+            scroll: driver::ScrollMode::default(),
+            graphic: driver::Graphic::StepGrid { steps, current },
+        }
+    }
+
+    // Tracks of the index with their kind and channel
+    fn tracks_page(&self) -> driver::DisplayText {
+        let mut lines: [driver::Line; 4] = Default::default();
+        lines[0] = line(format_args!("Tracks: {}", TRACKS.len()));
+        lines[1..]
+            .iter_mut()
+            .zip(TRACKS.iter().enumerate())
+            .for_each(|(l, (i, t))| {
+                *l = line(format_args!(
+                    "{} {} {:?} ch{}",
+                    i + 1,
+                    t.name,
+                    t.kind,
+                    t.channel
+                ))
+            });
+        driver::DisplayText {
+            lines,
+            scroll: driver::ScrollMode::default(),
+            graphic: driver::Graphic::None,
+        }
+    }
+
+    fn mixer_page(&self) -> driver::DisplayText {
+        let names = self.track_names();
+        let states: Vec<_> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let state = match self.mixer.state(i) {
+                    TrackState::On => "on",
+                    TrackState::Muted => "mute",
+                    TrackState::Solo => "solo",
+                    TrackState::Off => "--",
+                };
+                (name, state)
+            })
+            .collect();
+        let mut lines: [driver::Line; 4] = Default::default();
+        lines[0] = line(format_args!("Mixer"));
+        // Two tracks per line
+        lines[1..]
+            .iter_mut()
+            .zip(states.chunks(2))
+            .for_each(|(l, chunk)| {
+                *l = match chunk {
+                    [(a, sa), (b, sb)] => line(format_args!("{a:<5.5} {sa:<4}{b:<5.5} {sb}")),
+                    [(a, sa)] => line(format_args!("{a:<5.5} {sa}")),
+                    _ => line(format_args!("")),
+                }
+            });
+        driver::DisplayText {
+            lines,
+            scroll: driver::ScrollMode::default(),
+            graphic: driver::Graphic::None,
+        }
+    }
+
+    fn diagnostics_page(diagnostics: &Diagnostics) -> driver::DisplayText {
+        driver::DisplayText {
+            lines: [
+                line(format_args!("Diagnostics")),
+                line(format_args!("Heap: {}", diagnostics.heap_used)),
+                line(format_args!("Free: {}", diagnostics.heap_free)),
+                line(format_args!("Inputs: {}", diagnostics.inputs)),
+            ],
+            scroll: driver::ScrollMode::default(),
+            graphic: driver::Graphic::None,
         }
     }
 }
+
+// Returns `true` if `message` is addressed to the device itself
+fn is_control(message: &MidiMessage) -> bool {
+    match message {
+        MidiMessage::NoteOff { channel, .. }
+        | MidiMessage::NoteOn { channel, .. }
+        | MidiMessage::CC { channel, .. }
+        | MidiMessage::PC { channel, .. } => *channel == CONTROL_CHANNEL,
+        _ => false,
+    }
+}
+
+// Sends `instruction` on `channel`, 0 keeps the channel of the instruction
+fn with_channel(instruction: Instruction, channel: u8) -> Instruction {
+    if channel == 0 {
+        return instruction;
+    }
+    match instruction {
+        Instruction::PlayNote { midi_note, len, .. } => Instruction::PlayNote {
+            midi_note,
+            len,
+            channel_id: channel,
+        },
+        Instruction::StartNote { midi_note, .. } => Instruction::StartNote {
+            midi_note,
+            channel_id: channel,
+        },
+        Instruction::StopNote { midi_note, .. } => Instruction::StopNote {
+            midi_note,
+            channel_id: channel,
+        },
+        Instruction::SendCC {
+            parameter, value, ..
+        } => Instruction::SendCC {
+            channel_id: channel,
+            parameter,
+            value,
+        },
+        other => other,
+    }
+}
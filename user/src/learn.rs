@@ -0,0 +1,121 @@
+use alloc::vec::Vec;
+use log::{info, warn};
+use mseq_core::MidiMessage;
+
+use crate::conductor::CONTROL_CHANNEL;
+use crate::nrpn::Param;
+
+// Learned controllers, the oldest mapping is forgotten when a new one does not fit
+const MAX_MAPPINGS: usize = 32;
+
+/// Controller routed to a parameter.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Mapping {
+    pub channel: u8,
+    pub controller: u8,
+    pub param: Param,
+}
+
+/// Mapping of the controllers learned from the midi input to the parameters of the sequencer.
+#[derive(Default)]
+pub struct MidiLearn {
+    mappings: Vec<Mapping>,
+    // Parameter waiting for a controller
+    learning: Option<Param>,
+}
+
+impl MidiLearn {
+    /// Maps the next controller received to `param`.
+    pub fn start(&mut self, param: Param) {
+        info!("Learning {param:?}");
+        self.learning = Some(param);
+    }
+
+    pub fn cancel(&mut self) {
+        self.learning = None;
+    }
+
+    pub fn learning(&self) -> Option<Param> {
+        self.learning
+    }
+
+    /// Forgets every mapping.
+    pub fn clear(&mut self) {
+        self.mappings.clear();
+        self.learning = None;
+    }
+
+    /// Returns the channel and controller mapped to `param`.
+    pub fn mapping(&self, param: Param) -> Option<(u8, u8)> {
+        self.mappings
+            .iter()
+            .find(|m| m.param == param)
+            .map(|m| (m.channel, m.controller))
+    }
+
+    /// Learns the controller of `message` if a parameter waits for one, otherwise returns the
+    /// parameter mapped to it with the value of the controller.
+    /// Returns `None` if `message` is not a mapped controller. The controllers of the control
+    /// channel keep their function, they are never learned.
+    pub fn handle(&mut self, message: &MidiMessage) -> Option<Option<(Param, u8)>> {
+        let MidiMessage::CC {
+            channel,
+            controller,
+            value,
+        } = *message
+        else {
+            return None;
+        };
+        if channel == CONTROL_CHANNEL {
+            return None;
+        }
+        if let Some(param) = self.learning.take() {
+            info!("Channel {channel} CC {controller} mapped to {param:?}");
+            // A controller and a parameter have a single mapping
+            self.mappings
+                .retain(|m| m.param != param && (m.channel, m.controller) != (channel, controller));
+            if self.mappings.len() == MAX_MAPPINGS {
+                self.mappings.remove(0);
+            }
+            self.mappings.push(Mapping {
+                channel,
+                controller,
+                param,
+            });
+            return Some(None);
+        }
+        self.mappings
+            .iter()
+            .find(|m| (m.channel, m.controller) == (channel, controller))
+            .map(|m| Some((m.param, value)))
+    }
+
+    /// Serializes the mappings to be persisted, as channel, controller and parameter identifier.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.mappings
+            .iter()
+            .flat_map(|m| [m.channel, m.controller, m.param.id()])
+            .collect()
+    }
+
+    /// Restores the mappings serialized with [`MidiLearn::to_bytes`].
+    pub fn load(&mut self, bytes: &[u8]) {
+        self.mappings = bytes
+            .as_chunks::<3>()
+            .0
+            .iter()
+            .filter_map(|&[channel, controller, id]| {
+                let param = Param::from_id(id);
+                if param.is_none() {
+                    warn!("Unknown parameter {id} in the midi learn mapping");
+                }
+                param.map(|param| Mapping {
+                    channel,
+                    controller,
+                    param,
+                })
+            })
+            .take(MAX_MAPPINGS)
+            .collect();
+    }
+}
@@ -3,3 +3,14 @@
 extern crate alloc;
 
 pub mod conductor;
+pub mod learn;
+pub mod menu;
+pub mod mixer;
+pub mod nrpn;
+pub mod pages;
+pub mod recorder;
+pub mod scenes;
+pub mod settings;
+pub mod song;
+pub mod tracks;
+pub mod transpose;
@@ -0,0 +1,286 @@
+use alloc::string::String;
+use log::warn;
+
+use crate::learn::MidiLearn;
+use crate::nrpn::Param;
+use crate::pages::line;
+use crate::settings::{Setting, Settings};
+
+// Deepest menu that can be opened
+const MAX_DEPTH: usize = 4;
+// Entries shown below the title
+const VISIBLE_ENTRIES: usize = 3;
+
+/// Entry of a menu.
+pub enum Entry {
+    /// Sub menu with its own entries.
+    Menu(&'static str, &'static [Entry]),
+    /// Value edited with the encoder.
+    Setting(&'static str, Setting),
+    /// Sub menu with the midi channel of each track.
+    TrackChannels(&'static str),
+    /// Learns the controller of a parameter when pressed.
+    Learn(&'static str, Param),
+    /// Sub menu learning the controller of the mute of each track.
+    LearnMutes(&'static str),
+    /// Forgets the learned controllers when pressed.
+    ClearLearn(&'static str),
+}
+
+/// Change requested from the menu.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MenuAction {
+    /// The setting was changed and must be applied.
+    Setting(Setting),
+    /// The next controller received must be mapped to the parameter.
+    Learn(Param),
+    ClearLearn,
+}
+
+/// Menu opened by a long press on the encoder button.
+pub const MAIN_MENU: Entry = Entry::Menu(
+    "Settings",
+    &[
+        Entry::Menu(
+            "Sequencer",
+            &[
+                Entry::Setting("Bpm", Setting::Bpm),
+                Entry::Setting("Swing", Setting::Swing),
+                Entry::Setting("Song", Setting::SongMode),
+            ],
+        ),
+        Entry::Menu(
+            "Midi",
+            &[
+                Entry::Setting("Clock", Setting::ClockMode),
+                Entry::Setting("Thru", Setting::Thru),
+                Entry::Setting("Echo", Setting::NrpnEcho),
+            ],
+        ),
+        Entry::TrackChannels("Channels"),
+        Entry::Menu(
+            "Transpose",
+            &[
+                Entry::Setting("Channel", Setting::TransposeChannel),
+                Entry::Setting("Quantize", Setting::TransposeQuantize),
+                Entry::Setting("Latch", Setting::TransposeLatch),
+            ],
+        ),
+        Entry::Menu(
+            "Record",
+            &[
+                Entry::Setting("Grid", Setting::RecordGrid),
+                Entry::Setting("Mode", Setting::RecordMode),
+            ],
+        ),
+        Entry::Menu(
+            "Learn",
+            &[
+                Entry::Learn("Bpm", Param::Bpm),
+                Entry::Learn("Swing", Param::Swing),
+                Entry::Learn("Transpose", Param::Transpose),
+                Entry::LearnMutes("Mutes"),
+                Entry::ClearLearn("Clear"),
+            ],
+        ),
+    ],
+);
+
+/// Input of the menu, produced from the front panel events.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MenuInput {
+    /// Moves the cursor, or changes the value being edited.
+    Turn(i8),
+    /// Opens the entry under the cursor, or starts and stops editing a value.
+    Press,
+}
+
+// Line of a menu
+enum Child {
+    Back,
+    Entry(&'static Entry),
+    // Track of a sub menu by track
+    Track(&'static Entry, usize),
+}
+
+struct Level {
+    entry: &'static Entry,
+    selected: usize,
+}
+
+impl Level {
+    fn len(&self, track_count: usize) -> usize {
+        match self.entry {
+            Entry::Menu(_, entries) => entries.len() + 1,
+            Entry::TrackChannels(_) | Entry::LearnMutes(_) => track_count + 1,
+            Entry::Setting(..) | Entry::Learn(..) | Entry::ClearLearn(_) => 1,
+        }
+    }
+
+    fn child(&self, index: usize) -> Child {
+        match (self.entry, index) {
+            (_, 0) => Child::Back,
+            (Entry::Menu(_, entries), i) => Child::Entry(&entries[i - 1]),
+            (entry, i) => Child::Track(entry, i - 1),
+        }
+    }
+}
+
+/// Navigation state of the settings menu.
+pub struct Menu {
+    root: &'static Entry,
+    stack: heapless::Vec<Level, MAX_DEPTH>,
+    editing: bool,
+}
+
+impl Menu {
+    pub fn new(root: &'static Entry) -> Self {
+        Self {
+            root,
+            stack: heapless::Vec::new(),
+            editing: false,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.stack.is_empty()
+    }
+
+    /// Opens the root of the menu.
+    pub fn open(&mut self) {
+        self.close();
+        // The stack is empty
+        let _ = self.stack.push(Level {
+            entry: self.root,
+            selected: 0,
+        });
+    }
+
+    pub fn close(&mut self) {
+        self.stack.clear();
+        self.editing = false;
+    }
+
+    /// Applies `input` to the menu and `settings`.
+    /// Returns the change to apply, if any.
+    pub fn handle(
+        &mut self,
+        input: MenuInput,
+        settings: &mut Settings,
+        track_count: usize,
+    ) -> Option<MenuAction> {
+        let level = self.stack.last_mut()?;
+        let child = level.child(level.selected);
+        match input {
+            MenuInput::Turn(delta) if self.editing => {
+                let setting = Self::setting(&child)?;
+                settings.step(setting, delta);
+                return Some(MenuAction::Setting(setting));
+            }
+            MenuInput::Turn(delta) => {
+                let last = level.len(track_count) - 1;
+                level.selected = level
+                    .selected
+                    .saturating_add_signed(delta as isize)
+                    .min(last);
+            }
+            MenuInput::Press => match child {
+                Child::Back => {
+                    self.stack.pop();
+                }
+                Child::Entry(
+                    entry @ (Entry::Menu(..) | Entry::TrackChannels(_) | Entry::LearnMutes(_)),
+                ) => {
+                    if self.stack.push(Level { entry, selected: 0 }).is_err() {
+                        warn!("Menu too deep");
+                    }
+                }
+                Child::Entry(Entry::Learn(_, param)) => return Some(MenuAction::Learn(*param)),
+                Child::Track(Entry::LearnMutes(_), i) => {
+                    return Some(MenuAction::Learn(Param::Mute(i)));
+                }
+                Child::Entry(Entry::ClearLearn(_)) => return Some(MenuAction::ClearLearn),
+                Child::Entry(Entry::Setting(..)) | Child::Track(..) => self.editing = !self.editing,
+            },
+        }
+        None
+    }
+
+    /// Renders the current menu, `track_names` label the entries by track.
+    pub fn display_text(
+        &self,
+        settings: &Settings,
+        learn: &MidiLearn,
+        track_names: &[String],
+    ) -> driver::DisplayText {
+        let mut lines: [driver::Line; 4] = Default::default();
+        if let Some(level) = self.stack.last() {
+            lines[0] = line(format_args!("{}", Self::label(level.entry)));
+            let first = level.selected.saturating_sub(VISIBLE_ENTRIES - 1);
+            let last = level.len(track_names.len()).min(first + VISIBLE_ENTRIES);
+            lines[1..].iter_mut().zip(first..last).for_each(|(l, i)| {
+                let child = level.child(i);
+                let cursor = match (i == level.selected, self.editing) {
+                    (true, true) => '*',
+                    (true, false) => '>',
+                    _ => ' ',
+                };
+                let label = match &child {
+                    Child::Back => "..",
+                    Child::Entry(entry) => Self::label(entry),
+                    Child::Track(_, i) => track_names.get(*i).map_or("", |n| n.as_str()),
+                };
+                *l = match (Self::setting(&child), Self::param(&child)) {
+                    (Some(setting), _) => line(format_args!(
+                        "{cursor}{label:<10}{}",
+                        settings.format(setting)
+                    )),
+                    // Learned controller of the parameter, as channel and controller number
+                    (_, Some(param)) if learn.learning() == Some(param) => {
+                        line(format_args!("{cursor}{label:<10}..."))
+                    }
+                    (_, Some(param)) => match learn.mapping(param) {
+                        Some((channel, cc)) => {
+                            line(format_args!("{cursor}{label:<10}{channel}:{cc}"))
+                        }
+                        None => line(format_args!("{cursor}{label:<10}-")),
+                    },
+                    (None, None) => line(format_args!("{cursor}{label}")),
+                };
+            });
+        }
+        driver::DisplayText {
+            lines,
+            // The values must stay aligned
+            scroll: driver::ScrollMode::Truncate,
+            graphic: driver::Graphic::None,
+        }
+    }
+
+    fn label(entry: &Entry) -> &'static str {
+        match entry {
+            Entry::Menu(label, _)
+            | Entry::Setting(label, _)
+            | Entry::TrackChannels(label)
+            | Entry::Learn(label, _)
+            | Entry::LearnMutes(label)
+            | Entry::ClearLearn(label) => label,
+        }
+    }
+
+    fn setting(child: &Child) -> Option<Setting> {
+        match child {
+            Child::Entry(Entry::Setting(_, setting)) => Some(*setting),
+            Child::Track(Entry::TrackChannels(_), i) => Some(Setting::TrackChannel(*i)),
+            _ => None,
+        }
+    }
+
+    fn param(child: &Child) -> Option<Param> {
+        match child {
+            Child::Entry(Entry::Learn(_, param)) => Some(*param),
+            Child::Track(Entry::LearnMutes(_), i) => Some(Param::Mute(*i)),
+            _ => None,
+        }
+    }
+}
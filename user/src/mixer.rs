@@ -0,0 +1,132 @@
+use mseq_core::MidiMessage;
+
+use crate::settings::MAX_TRACKS;
+
+// Controller values from this one enable a state
+const CC_ON: u8 = 64;
+
+/// Midi messages controlling one state of the tracks, the first track uses `first`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MapControl {
+    /// Each Note On of note `first + track` toggles the state.
+    Note { first: u8 },
+    /// Controller `first + track` enables the state from value 64.
+    Cc { first: u8 },
+}
+
+impl MapControl {
+    // Returns the track controlled by `number` if it is in the range of `first`
+    fn track(first: u8, number: u8) -> Option<usize> {
+        let track = number.checked_sub(first)? as usize;
+        (track < MAX_TRACKS).then_some(track)
+    }
+
+    fn note_track(&self, note: u8) -> Option<usize> {
+        match *self {
+            MapControl::Note { first } => Self::track(first, note),
+            MapControl::Cc { .. } => None,
+        }
+    }
+
+    fn cc_track(&self, controller: u8) -> Option<usize> {
+        match *self {
+            MapControl::Cc { first } => Self::track(first, controller),
+            MapControl::Note { .. } => None,
+        }
+    }
+}
+
+/// Midi map of the mute and solo of the tracks.
+pub struct MixerMap {
+    pub channel: u8,
+    pub mute: MapControl,
+    pub solo: MapControl,
+}
+
+/// Notes 36-51 toggle the mutes and notes 52-67 the solos, on channel 16.
+pub const MIXER_MAP: MixerMap = MixerMap {
+    channel: 16,
+    mute: MapControl::Note { first: 36 },
+    solo: MapControl::Note { first: 52 },
+};
+
+/// State of a track in the mixer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrackState {
+    On,
+    Muted,
+    Solo,
+    /// Silenced because another track is solo.
+    Off,
+}
+
+/// Mute and solo state of every track.
+#[derive(Default)]
+pub struct Mixer {
+    muted: [bool; MAX_TRACKS],
+    solo: [bool; MAX_TRACKS],
+}
+
+impl Mixer {
+    /// Applies `message` if it belongs to `map`, returns `true` if it did.
+    pub fn handle(&mut self, map: &MixerMap, message: &MidiMessage) -> bool {
+        match *message {
+            MidiMessage::NoteOn { channel, note } if channel == map.channel => {
+                let note = note.midi_value();
+                if let Some(track) = map.mute.note_track(note) {
+                    self.muted[track] = !self.muted[track];
+                } else if let Some(track) = map.solo.note_track(note) {
+                    self.solo[track] = !self.solo[track];
+                } else {
+                    return false;
+                }
+                true
+            }
+            // Releasing a key of the map does nothing
+            MidiMessage::NoteOff { channel, note } if channel == map.channel => {
+                let note = note.midi_value();
+                map.mute.note_track(note).is_some() || map.solo.note_track(note).is_some()
+            }
+            MidiMessage::CC {
+                channel,
+                controller,
+                value,
+            } if channel == map.channel => {
+                if let Some(track) = map.mute.cc_track(controller) {
+                    self.muted[track] = value >= CC_ON;
+                } else if let Some(track) = map.solo.cc_track(controller) {
+                    self.solo[track] = value >= CC_ON;
+                } else {
+                    return false;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn state(&self, track: usize) -> TrackState {
+        let any_solo = self.solo.iter().any(|s| *s);
+        match (self.solo.get(track), self.muted.get(track)) {
+            (Some(true), _) => TrackState::Solo,
+            _ if any_solo => TrackState::Off,
+            (_, Some(true)) => TrackState::Muted,
+            _ => TrackState::On,
+        }
+    }
+
+    pub fn is_muted(&self, track: usize) -> bool {
+        self.muted.get(track).copied().unwrap_or(false)
+    }
+
+    pub fn set_muted(&mut self, track: usize, muted: bool) {
+        if let Some(m) = self.muted.get_mut(track) {
+            *m = muted;
+        }
+    }
+
+    /// Returns `true` if `track` can be heard.
+    pub fn audible(&self, track: usize) -> bool {
+        matches!(self.state(track), TrackState::On | TrackState::Solo)
+    }
+}
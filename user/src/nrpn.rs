@@ -0,0 +1,198 @@
+use alloc::vec::Vec;
+use mseq_core::MidiMessage;
+
+use crate::conductor::CONTROL_CHANNEL;
+use crate::settings::{MAX_TRACKS, Setting, Settings};
+
+// Controllers of the NRPN and RPN messages
+const NRPN_MSB_CC: u8 = 99;
+const NRPN_LSB_CC: u8 = 98;
+const RPN_MSB_CC: u8 = 101;
+const RPN_LSB_CC: u8 = 100;
+const DATA_MSB_CC: u8 = 6;
+const DATA_LSB_CC: u8 = 38;
+
+// Most significant byte of the addresses of the parameters by track
+const MUTE_MSB: u8 = 1;
+const CHANNEL_MSB: u8 = 2;
+
+/// Sequencer parameter that can be changed by NRPN on the control channel, or by a learned
+/// controller.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Param {
+    /// Address 0/0, the 14-bit value is the BPM.
+    Bpm,
+    /// Address 0/1.
+    Swing,
+    /// Address 0/2, 0 plays the tracks at their root and 1-12 set the key from C to B.
+    Transpose,
+    /// Address 1/track, the track is muted from 64.
+    Mute(usize),
+    /// Address 2/track, midi channel of the track and 0 keeps the channel of the track.
+    Channel(usize),
+}
+
+impl Param {
+    /// Every parameter, in the order in which the changes are echoed.
+    pub fn all() -> impl Iterator<Item = Param> {
+        [Param::Bpm, Param::Swing, Param::Transpose]
+            .into_iter()
+            .chain((0..MAX_TRACKS).map(Param::Mute))
+            .chain((0..MAX_TRACKS).map(Param::Channel))
+    }
+
+    /// Identifier of the parameter, its index in [`Param::all`].
+    pub fn id(&self) -> u8 {
+        Param::all().position(|p| p == *self).unwrap_or(0) as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Param::all().nth(id as usize)
+    }
+
+    /// Scales the value of a controller to the range of the parameter.
+    pub fn scale(&self, value: u8) -> u8 {
+        let (min, max) = match *self {
+            Param::Bpm => Settings::range(Setting::Bpm),
+            Param::Swing => Settings::range(Setting::Swing),
+            Param::Transpose => (0, 12),
+            Param::Mute(_) => (0, 127),
+            Param::Channel(track) => Settings::range(Setting::TrackChannel(track)),
+        };
+        min + ((max - min) as u16 * value.min(127) as u16 / 127) as u8
+    }
+
+    fn from_address(msb: u8, lsb: u8) -> Option<Self> {
+        let track = lsb as usize;
+        match (msb, lsb) {
+            (0, 0) => Some(Param::Bpm),
+            (0, 1) => Some(Param::Swing),
+            (0, 2) => Some(Param::Transpose),
+            (MUTE_MSB, _) if track < MAX_TRACKS => Some(Param::Mute(track)),
+            (CHANNEL_MSB, _) if track < MAX_TRACKS => Some(Param::Channel(track)),
+            _ => None,
+        }
+    }
+
+    fn address(&self) -> (u8, u8) {
+        match *self {
+            Param::Bpm => (0, 0),
+            Param::Swing => (0, 1),
+            Param::Transpose => (0, 2),
+            Param::Mute(track) => (MUTE_MSB, track as u8),
+            Param::Channel(track) => (CHANNEL_MSB, track as u8),
+        }
+    }
+
+    /// Returns `true` if the parameter takes a 14-bit value, the others only use the data entry
+    /// MSB.
+    fn is_fine(&self) -> bool {
+        *self == Param::Bpm
+    }
+
+    /// Returns the messages setting the parameter to `value`, on the control channel.
+    pub fn messages(&self, value: u8) -> Vec<MidiMessage> {
+        let (msb, lsb) = self.address();
+        let data = if self.is_fine() {
+            [value >> 7, value & 0x7f]
+        } else {
+            [value, 0]
+        };
+        [
+            (NRPN_MSB_CC, msb),
+            (NRPN_LSB_CC, lsb),
+            (DATA_MSB_CC, data[0]),
+            (DATA_LSB_CC, data[1]),
+        ]
+        .into_iter()
+        .map(|(controller, value)| MidiMessage::CC {
+            channel: CONTROL_CHANNEL,
+            controller,
+            value,
+        })
+        .collect()
+    }
+}
+
+/// Value received for a parameter.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Nrpn {
+    pub param: Param,
+    pub value: u16,
+}
+
+impl Nrpn {
+    /// Returns the value applied to the parameter: the data entry MSB, or the 14-bit value
+    /// clamped.
+    pub fn data(&self) -> u8 {
+        if self.param.is_fine() {
+            self.value.min(u8::MAX as u16) as u8
+        } else {
+            (self.value >> 7) as u8
+        }
+    }
+}
+
+/// Decodes the NRPN messages of the control channel.
+/// The value is applied at each data entry message: the MSB resets the LSB as in the MIDI
+/// specification, and the LSB completes the 14-bit value.
+#[derive(Default)]
+pub struct NrpnDecoder {
+    // Address selected by CC 99 and 98, `None` after a RPN
+    address: Option<(u8, u8)>,
+    value: u16,
+}
+
+impl NrpnDecoder {
+    pub const fn new() -> Self {
+        Self {
+            address: None,
+            value: 0,
+        }
+    }
+
+    /// Returns `None` if `message` is not part of a NRPN, or the received value once a data entry
+    /// completes it.
+    pub fn decode(&mut self, message: &MidiMessage) -> Option<Option<Nrpn>> {
+        let MidiMessage::CC {
+            channel: CONTROL_CHANNEL,
+            controller,
+            value,
+        } = *message
+        else {
+            return None;
+        };
+        match controller {
+            NRPN_MSB_CC => {
+                self.address = Some((value, self.address.map_or(0, |a| a.1)));
+                Some(None)
+            }
+            NRPN_LSB_CC => {
+                self.address = Some((self.address.map_or(0, |a| a.0), value));
+                Some(None)
+            }
+            // The data entries of a RPN do not apply to the sequencer
+            RPN_MSB_CC | RPN_LSB_CC => {
+                self.address = None;
+                Some(None)
+            }
+            DATA_MSB_CC => {
+                self.value = (value as u16) << 7;
+                Some(self.nrpn())
+            }
+            DATA_LSB_CC => {
+                self.value = (self.value & !0x7f) | value as u16;
+                Some(self.nrpn())
+            }
+            _ => None,
+        }
+    }
+
+    fn nrpn(&self) -> Option<Nrpn> {
+        let (msb, lsb) = self.address?;
+        Param::from_address(msb, lsb).map(|param| Nrpn {
+            param,
+            value: self.value,
+        })
+    }
+}
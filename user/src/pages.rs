@@ -0,0 +1,66 @@
+use core::fmt::Write;
+
+/// Screens that can be shown on the display.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Page {
+    #[default]
+    Transport,
+    Tracks,
+    Mixer,
+    Diagnostics,
+}
+
+impl Page {
+    /// Returns the page following `self`, wrapping around after the last one.
+    pub fn next(self) -> Self {
+        match self {
+            Page::Transport => Page::Tracks,
+            Page::Tracks => Page::Mixer,
+            Page::Mixer => Page::Diagnostics,
+            Page::Diagnostics => Page::Transport,
+        }
+    }
+}
+
+/// System information gathered by the kernel for the diagnostics page.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Diagnostics {
+    pub heap_used: usize,
+    pub heap_free: usize,
+    pub inputs: u32,
+}
+
+/// Builds a display line from `args`.
+/// Lines wider than the screen are scrolled by the driver, only the text beyond
+/// [`driver::LINE_CAPACITY`] is dropped.
+pub fn line(args: core::fmt::Arguments) -> driver::Line {
+    let mut line = Line(heapless::String::new());
+    // Errors only mean the text was truncated
+    let _ = line.write_fmt(args);
+    line.0
+}
+
+struct Line(driver::Line);
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            self.0.push(c).map_err(|_| core::fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Controls when the kernel redraws the display.
+pub struct RefreshPolicy {
+    /// Time between two periodic refreshes, in milliseconds.
+    pub frame_period_ms: u32,
+    /// Refresh immediately after an input was handled.
+    pub refresh_on_input: bool,
+}
+
+/// Refresh the display at 10 Hz and whenever an input is received.
+pub const REFRESH_POLICY: RefreshPolicy = RefreshPolicy {
+    frame_period_ms: 100,
+    refresh_on_input: true,
+};
@@ -0,0 +1,179 @@
+use alloc::vec::Vec;
+use mseq_core::{DeteTrack, MidiNote, Note};
+
+use crate::settings::RecordMode;
+
+/// Length of the recorded track, in steps.
+pub const RECORD_LEN: u32 = 2 * 96;
+/// Name of the recorded track.
+pub const RECORD_NAME: &str = "rec";
+
+// Channel of the recorded track until a note is recorded
+const DEFAULT_CHANNEL: u8 = 1;
+
+#[derive(Clone, Copy)]
+struct RecordedNote {
+    midi_note: MidiNote,
+    start: u32,
+    len: u32,
+    // Recording pass in which the note was played
+    pass: u32,
+}
+
+// Note being held on the keyboard
+struct PressedNote {
+    midi_note: MidiNote,
+    start: u32,
+    pressed_at: u32,
+}
+
+/// Records the notes played on the midi input into a looping track.
+pub struct Recorder {
+    notes: Vec<RecordedNote>,
+    pressed: Vec<PressedNote>,
+    armed: bool,
+    pass: u32,
+    channel: u8,
+    track: DeteTrack,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self {
+            notes: Vec::new(),
+            pressed: Vec::new(),
+            armed: false,
+            pass: 0,
+            channel: DEFAULT_CHANNEL,
+            track: DeteTrack::new(
+                RECORD_LEN,
+                Vec::new(),
+                Note::C,
+                DEFAULT_CHANNEL,
+                RECORD_NAME,
+            ),
+        }
+    }
+}
+
+impl Recorder {
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    /// Starts or stops recording. Each recording starts a new pass.
+    pub fn arm(&mut self, armed: bool) {
+        if armed && !self.armed {
+            self.pass += 1;
+        }
+        self.armed = armed;
+        self.pressed.clear();
+    }
+
+    /// Erases the recorded notes.
+    pub fn clear(&mut self) {
+        self.notes.clear();
+        self.pressed.clear();
+        self.rebuild();
+    }
+
+    /// Records the start of a note at `step`, moved to the nearest point of the `grid` (in steps).
+    pub fn note_on(&mut self, midi_note: MidiNote, channel: u8, step: u32, grid: u32) {
+        if !self.armed {
+            return;
+        }
+        let grid = grid.max(1);
+        let start = ((step % RECORD_LEN + grid / 2) / grid * grid) % RECORD_LEN;
+        self.channel = channel;
+        self.pressed
+            .retain(|n| n.midi_note.midi_value() != midi_note.midi_value());
+        self.pressed.push(PressedNote {
+            midi_note,
+            start,
+            pressed_at: step,
+        });
+    }
+
+    /// Records the end of a note at `step`, its length is rounded to the `grid` (in steps).
+    pub fn note_off(&mut self, midi_note: MidiNote, step: u32, grid: u32) {
+        let Some(i) = self
+            .pressed
+            .iter()
+            .position(|n| n.midi_note.midi_value() == midi_note.midi_value())
+        else {
+            return;
+        };
+        let pressed = self.pressed.swap_remove(i);
+        let grid = grid.max(1);
+        let held = step.saturating_sub(pressed.pressed_at);
+        let len = ((held + grid / 2) / grid * grid).clamp(grid, RECORD_LEN);
+        self.notes.push(RecordedNote {
+            midi_note: pressed.midi_note,
+            start: pressed.start,
+            len,
+            pass: self.pass,
+        });
+        self.rebuild();
+    }
+
+    /// In replace mode, erases the notes of the previous passes starting at `step`.
+    pub fn update(&mut self, step: u32, mode: RecordMode) {
+        if !self.armed || mode != RecordMode::Replace {
+            return;
+        }
+        let position = step % RECORD_LEN;
+        let count = self.notes.len();
+        self.notes
+            .retain(|n| n.start != position || n.pass == self.pass);
+        if self.notes.len() != count {
+            self.rebuild();
+        }
+    }
+
+    pub fn track(&self) -> &DeteTrack {
+        &self.track
+    }
+
+    pub fn track_mut(&mut self) -> &mut DeteTrack {
+        &mut self.track
+    }
+
+    /// Serializes the recorded notes to be persisted.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let notes: Vec<_> = self
+            .notes
+            .iter()
+            .map(|n| (n.midi_note, n.start, n.len))
+            .collect();
+        // Serializing to a vector does not fail
+        postcard::to_allocvec(&(self.channel, notes)).unwrap_or_default()
+    }
+
+    /// Replaces the recorded notes by the ones serialized with [`Recorder::to_bytes`].
+    pub fn load(&mut self, bytes: &[u8]) -> Result<(), postcard::Error> {
+        let (channel, notes): (u8, Vec<(MidiNote, u32, u32)>) = postcard::from_bytes(bytes)?;
+        self.channel = channel;
+        self.notes = notes
+            .into_iter()
+            .map(|(midi_note, start, len)| RecordedNote {
+                midi_note,
+                start: start % RECORD_LEN,
+                len,
+                pass: 0,
+            })
+            .collect();
+        self.pressed.clear();
+        self.rebuild();
+        Ok(())
+    }
+
+    // The track is rebuilt when the notes change so that it plays back immediately
+    fn rebuild(&mut self) {
+        let notes = self
+            .notes
+            .iter()
+            .map(|n| (n.midi_note, n.start, n.len))
+            .collect();
+        self.track = DeteTrack::new(RECORD_LEN, notes, Note::C, self.channel, RECORD_NAME);
+    }
+}
@@ -0,0 +1,9 @@
+/// Set of tracks played together, selected by a Program Change on the control channel.
+pub struct Scene {
+    pub name: &'static str,
+    /// Names of the tracks of the scene.
+    pub tracks: &'static [&'static str],
+}
+
+// Scenes declared in res/index.toml
+include!("../../track_bin/scenes.rs");
@@ -0,0 +1,278 @@
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// Maximum number of tracks with a configurable midi channel.
+pub const MAX_TRACKS: usize = 16;
+
+/// Source of the midi clock.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ClockMode {
+    /// The internal clock drives the sequencer and is sent on the midi output.
+    Master,
+    /// The sequencer follows the clock received on the midi input.
+    Slave,
+}
+
+/// When a change of the transposition key is applied.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Quantize {
+    /// At the next step.
+    Step,
+    /// At the start of the next bar.
+    Bar,
+}
+
+/// How a recording changes the notes already recorded.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RecordMode {
+    /// The new notes are added to the recorded ones.
+    Overdub,
+    /// The recorded notes are erased as the recording goes over them.
+    Replace,
+}
+
+// Grids of the recording in steps, with their names
+const RECORD_GRIDS: [(u32, &str); 4] = [(3, "1/32"), (6, "1/16"), (12, "1/8"), (24, "1/4")];
+
+// Settings saved by `Settings::to_bytes` with their index as identifier, new ones go at the end.
+// The clock mode is not saved as the master switch sets it at power on, its identifier stays
+// reserved.
+const SAVED: [Setting; 11] = [
+    Setting::Bpm,
+    Setting::Swing,
+    Setting::ClockMode,
+    Setting::Thru,
+    Setting::TransposeChannel,
+    Setting::TransposeQuantize,
+    Setting::TransposeLatch,
+    Setting::RecordGrid,
+    Setting::RecordMode,
+    Setting::SongMode,
+    Setting::NrpnEcho,
+];
+// Identifier of the midi channel of the first track
+const TRACK_CHANNEL_ID: u8 = 0x80;
+
+/// Settings that can be edited from the menu.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Setting {
+    Bpm,
+    /// Delay of the odd sixteenth notes, 50% is straight and 75% is a full triplet feel.
+    Swing,
+    ClockMode,
+    /// Forward the midi input to the midi output.
+    Thru,
+    /// Midi channel of the track at this index, 0 keeps the channel of the track.
+    TrackChannel(usize),
+    /// Midi channel of the keyboard transposing the tracks, 0 disables the transposition.
+    TransposeChannel,
+    TransposeQuantize,
+    /// Keep the last key after it is released instead of going back to the root.
+    TransposeLatch,
+    /// Index of the grid on which the recorded notes are quantized.
+    RecordGrid,
+    RecordMode,
+    /// Play the song of the index instead of staying on the selected scene.
+    SongMode,
+    /// Send the changes of the NRPN parameters on the midi output.
+    NrpnEcho,
+}
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub bpm: u8,
+    pub swing: u8,
+    pub clock_mode: ClockMode,
+    pub thru: bool,
+    pub track_channels: [u8; MAX_TRACKS],
+    pub transpose_channel: u8,
+    pub transpose_quantize: Quantize,
+    pub transpose_latch: bool,
+    pub record_grid: u8,
+    pub record_mode: RecordMode,
+    pub song_mode: bool,
+    pub nrpn_echo: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            bpm: 120,
+            swing: 50,
+            clock_mode: ClockMode::Master,
+            thru: false,
+            track_channels: [0; MAX_TRACKS],
+            transpose_channel: 0,
+            transpose_quantize: Quantize::Step,
+            transpose_latch: true,
+            record_grid: 1,
+            record_mode: RecordMode::Overdub,
+            song_mode: false,
+            nrpn_echo: false,
+        }
+    }
+}
+
+impl Settings {
+    /// Returns the value of `setting`.
+    pub fn get(&self, setting: Setting) -> u8 {
+        match setting {
+            Setting::Bpm => self.bpm,
+            Setting::Swing => self.swing,
+            Setting::ClockMode => self.clock_mode as u8,
+            Setting::Thru => self.thru as u8,
+            Setting::TrackChannel(i) => self.track_channels.get(i).copied().unwrap_or(0),
+            Setting::TransposeChannel => self.transpose_channel,
+            Setting::TransposeQuantize => self.transpose_quantize as u8,
+            Setting::TransposeLatch => self.transpose_latch as u8,
+            Setting::RecordGrid => self.record_grid,
+            Setting::RecordMode => self.record_mode as u8,
+            Setting::SongMode => self.song_mode as u8,
+            Setting::NrpnEcho => self.nrpn_echo as u8,
+        }
+    }
+
+    /// Sets the value of `setting`, clamped to its range.
+    pub fn set(&mut self, setting: Setting, value: u8) {
+        let (min, max) = Self::range(setting);
+        let value = value.clamp(min, max);
+        match setting {
+            Setting::Bpm => self.bpm = value,
+            Setting::Swing => self.swing = value,
+            Setting::ClockMode => {
+                self.clock_mode = if value == 0 {
+                    ClockMode::Master
+                } else {
+                    ClockMode::Slave
+                }
+            }
+            Setting::Thru => self.thru = value != 0,
+            Setting::TrackChannel(i) => {
+                if let Some(channel) = self.track_channels.get_mut(i) {
+                    *channel = value;
+                }
+            }
+            Setting::TransposeChannel => self.transpose_channel = value,
+            Setting::TransposeQuantize => {
+                self.transpose_quantize = if value == 0 {
+                    Quantize::Step
+                } else {
+                    Quantize::Bar
+                }
+            }
+            Setting::TransposeLatch => self.transpose_latch = value != 0,
+            Setting::RecordGrid => self.record_grid = value,
+            Setting::RecordMode => {
+                self.record_mode = if value == 0 {
+                    RecordMode::Overdub
+                } else {
+                    RecordMode::Replace
+                }
+            }
+            Setting::SongMode => self.song_mode = value != 0,
+            Setting::NrpnEcho => self.nrpn_echo = value != 0,
+        }
+    }
+
+    /// Adds `delta` to the value of `setting`.
+    pub fn step(&mut self, setting: Setting, delta: i8) {
+        let value = self.get(setting).saturating_add_signed(delta);
+        self.set(setting, value);
+    }
+
+    /// Serializes the settings to be persisted, as pairs of identifier and value.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let saved = SAVED
+            .iter()
+            .enumerate()
+            .filter(|(_, setting)| **setting != Setting::ClockMode)
+            .map(|(id, setting)| (id as u8, self.get(*setting)));
+        let channels = (0..MAX_TRACKS).map(|i| {
+            (
+                TRACK_CHANNEL_ID + i as u8,
+                self.get(Setting::TrackChannel(i)),
+            )
+        });
+        saved
+            .chain(channels)
+            .flat_map(|(id, value)| [id, value])
+            .collect()
+    }
+
+    /// Sets the values serialized with [`Settings::to_bytes`], unknown settings are ignored.
+    pub fn load(&mut self, bytes: &[u8]) {
+        for &[id, value] in bytes.as_chunks::<2>().0 {
+            let setting = match id.checked_sub(TRACK_CHANNEL_ID) {
+                Some(track) => Some(Setting::TrackChannel(track as usize)),
+                None => SAVED.get(id as usize).copied(),
+            };
+            if let Some(setting) = setting
+                && setting != Setting::ClockMode
+            {
+                self.set(setting, value);
+            }
+        }
+    }
+
+    /// Returns the grid of the recording in steps.
+    pub fn record_grid_steps(&self) -> u32 {
+        RECORD_GRIDS[self.record_grid as usize % RECORD_GRIDS.len()].0
+    }
+
+    /// Formats the value of `setting` for the display.
+    pub fn format(&self, setting: Setting) -> heapless::String<8> {
+        let mut text = heapless::String::new();
+        let value = self.get(setting);
+        // The longest value fits in the string
+        let _ = match setting {
+            Setting::Bpm => write!(text, "{value}"),
+            Setting::Swing => write!(text, "{value}%"),
+            Setting::ClockMode => match self.clock_mode {
+                ClockMode::Master => write!(text, "Master"),
+                ClockMode::Slave => write!(text, "Slave"),
+            },
+            Setting::Thru => write!(text, "{}", if self.thru { "On" } else { "Off" }),
+            Setting::TrackChannel(_) if value == 0 => write!(text, "Track"),
+            Setting::TrackChannel(_) => write!(text, "{value}"),
+            Setting::TransposeChannel if value == 0 => write!(text, "Off"),
+            Setting::TransposeChannel => write!(text, "{value}"),
+            Setting::TransposeQuantize => match self.transpose_quantize {
+                Quantize::Step => write!(text, "Step"),
+                Quantize::Bar => write!(text, "Bar"),
+            },
+            Setting::TransposeLatch => {
+                write!(text, "{}", if self.transpose_latch { "On" } else { "Off" })
+            }
+            Setting::RecordGrid => {
+                write!(
+                    text,
+                    "{}",
+                    RECORD_GRIDS[value as usize % RECORD_GRIDS.len()].1
+                )
+            }
+            Setting::RecordMode => match self.record_mode {
+                RecordMode::Overdub => write!(text, "Overdub"),
+                RecordMode::Replace => write!(text, "Replace"),
+            },
+            Setting::SongMode => write!(text, "{}", if self.song_mode { "On" } else { "Off" }),
+            Setting::NrpnEcho => write!(text, "{}", if self.nrpn_echo { "On" } else { "Off" }),
+        };
+        text
+    }
+
+    pub(crate) fn range(setting: Setting) -> (u8, u8) {
+        match setting {
+            Setting::Bpm => (20, 250),
+            Setting::Swing => (50, 75),
+            Setting::ClockMode
+            | Setting::Thru
+            | Setting::TransposeQuantize
+            | Setting::TransposeLatch
+            | Setting::RecordMode
+            | Setting::SongMode
+            | Setting::NrpnEcho => (0, 1),
+            Setting::RecordGrid => (0, RECORD_GRIDS.len() as u8 - 1),
+            Setting::TrackChannel(_) | Setting::TransposeChannel => (0, 16),
+        }
+    }
+}
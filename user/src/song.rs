@@ -0,0 +1,65 @@
+/// Part of a song, plays a scene for a number of bars.
+pub struct Part {
+    /// Index of the scene in [`crate::scenes::SCENES`].
+    pub scene: usize,
+    pub bars: u32,
+}
+
+/// Arrangement of scenes.
+/// After the part `loop_end` the song goes back to the part `loop_start`.
+pub struct Song {
+    pub parts: &'static [Part],
+    pub loop_start: usize,
+    pub loop_end: usize,
+}
+
+// Song declared in res/index.toml
+include!("../../track_bin/song.rs");
+
+/// Position of the conductor in a song.
+#[derive(Default)]
+pub struct SongPlayer {
+    part: usize,
+    bar: u32,
+    jump: bool,
+}
+
+impl SongPlayer {
+    /// Goes back to the first part, returns its scene.
+    pub fn restart(&mut self, song: &Song) -> Option<usize> {
+        self.part = 0;
+        self.bar = 0;
+        self.jump = false;
+        song.parts.first().map(|part| part.scene)
+    }
+
+    /// Advances by one bar.
+    /// Returns the scene to play when the part changes.
+    pub fn bar(&mut self, song: &Song) -> Option<usize> {
+        if song.parts.is_empty() {
+            return None;
+        }
+        self.bar += 1;
+        if !self.jump && self.bar < song.parts[self.part].bars {
+            return None;
+        }
+        self.jump = false;
+        self.bar = 0;
+        self.part = if self.part >= song.loop_end {
+            song.loop_start
+        } else {
+            self.part + 1
+        };
+        Some(song.parts[self.part].scene)
+    }
+
+    /// Goes to the next part at the start of the next bar.
+    pub fn next_part(&mut self) {
+        self.jump = true;
+    }
+
+    /// Returns the current part and bar in the part, both starting at 0.
+    pub fn position(&self) -> (usize, u32) {
+        (self.part, self.bar)
+    }
+}
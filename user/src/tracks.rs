@@ -0,0 +1,60 @@
+use alloc::vec::Vec;
+use mseq_core::MidiNote;
+use thiserror::Error;
+
+/// Generator used to build a track from its file.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrackKind {
+    Acid,
+    Arp,
+    Div,
+    Midi,
+}
+
+impl TrackKind {
+    /// Returns `true` if the tracks of this kind have a root and can be transposed.
+    pub fn has_root(self) -> bool {
+        !matches!(self, TrackKind::Div)
+    }
+}
+
+/// Track declared in res/index.toml, serialized at build time.
+pub struct TrackEntry {
+    pub name: &'static str,
+    pub channel: u8,
+    pub kind: TrackKind,
+    /// `DeteTrack` serialized with postcard.
+    pub bytes: &'static [u8],
+}
+
+#[derive(Error, Debug)]
+pub enum TrackError {
+    #[error("No track in slot {0}.")]
+    Slot(usize),
+    #[error("Invalid track: {0}.")]
+    Decode(#[from] postcard::Error),
+    #[error("Track of length 0.")]
+    Empty,
+    #[error("Note at step {step} beyond the track length {len}.")]
+    Step { step: u32, len: u32 },
+}
+
+// Fields of a serialized `DeteTrack` that are checked before it is played: length, notes as
+// (note, start step, length), and start step
+type TrackHead = (u32, Vec<(MidiNote, u32, u32)>, u32);
+
+/// Checks that a `DeteTrack` serialized with postcard can be played: `DeteTrack::play_step`
+/// divides the step by the length of the track.
+pub fn validate(bytes: &[u8]) -> Result<(), TrackError> {
+    let ((len, notes, _), _) = postcard::take_from_bytes::<TrackHead>(bytes)?;
+    if len == 0 {
+        return Err(TrackError::Empty);
+    }
+    match notes.iter().find(|(_, step, _)| *step >= len) {
+        Some(&(_, step, _)) => Err(TrackError::Step { step, len }),
+        None => Ok(()),
+    }
+}
+
+// Tracks declared in res/index.toml, in the order of the index
+include!("../../track_bin/tracks.rs");
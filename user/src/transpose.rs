@@ -0,0 +1,64 @@
+use mseq_core::Note;
+
+use crate::settings::Quantize;
+
+// Keys remembered while held, the oldest ones are forgotten
+const MAX_HELD_KEYS: usize = 8;
+const BAR: u32 = 96;
+
+/// Key transposition of the tracks from a keyboard.
+/// The last key held sets the key, the tracks play at their root when no key is set.
+#[derive(Default)]
+pub struct Transposer {
+    held: heapless::Vec<Note, MAX_HELD_KEYS>,
+    key: Option<Note>,
+    // Key waiting for the quantization
+    pending: Option<Option<Note>>,
+}
+
+impl Transposer {
+    pub fn key_on(&mut self, note: Note) {
+        self.held.retain(|n| *n != note);
+        if self.held.is_full() {
+            self.held.remove(0);
+        }
+        // There is room left
+        let _ = self.held.push(note);
+        self.pending = Some(Some(note));
+    }
+
+    /// With `latch` the key stays set once every key is released.
+    pub fn key_off(&mut self, note: Note, latch: bool) {
+        self.held.retain(|n| *n != note);
+        match self.held.last() {
+            Some(last) => self.pending = Some(Some(*last)),
+            None if !latch => self.pending = Some(None),
+            None => (),
+        }
+    }
+
+    /// Sets the key without a keyboard, `None` goes back to the root.
+    pub fn set_key(&mut self, key: Option<Note>) {
+        self.held.clear();
+        self.pending = Some(key);
+    }
+
+    /// Returns the new key if it changes at `step`.
+    pub fn update(&mut self, step: u32, quantize: Quantize) -> Option<Option<Note>> {
+        if quantize == Quantize::Bar && !step.is_multiple_of(BAR) {
+            return None;
+        }
+        let key = self.pending.take().filter(|key| *key != self.key)?;
+        self.key = key;
+        Some(key)
+    }
+
+    pub fn key(&self) -> Option<Note> {
+        self.key
+    }
+
+    /// Returns the key once the pending change is applied.
+    pub fn next_key(&self) -> Option<Note> {
+        self.pending.unwrap_or(self.key)
+    }
+}